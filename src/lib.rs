@@ -1,33 +1,233 @@
 use serde::{Deserialize, Serialize};
-use std::sync::{Arc, Mutex};
-use std::collections::HashMap;
-use tokio::io::AsyncBufReadExt;
-use tokio::task::JoinError;
+use parking_lot::Mutex;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::future::Future;
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+use tokio::sync::{broadcast, Notify};
+use tokio::task::{JoinError, JoinHandle};
+use tokio::time::Instant;
+
+
+/// Default capacity of the readers that capture step output.
+pub const DEFAULT_BUFFER_SIZE: usize = 8 * 1024;
+
+/// Default prefix of output lines that report step progress, e.g. `::progress::75`.
+pub const DEFAULT_PROGRESS_PREFIX: &str = "::progress::";
+
+/// Default number of past runs a `RunStore` keeps.
+pub const DEFAULT_RUN_HISTORY: usize = 20;
+
+/// Lines of a failed step's output to carry in its error.
+pub const FAILED_OUTPUT_LINES: usize = 20;
+
+/// Prefix of output lines that set a job output, e.g. `::output::tag=v1.2`.
+pub const OUTPUT_PREFIX: &str = "::output::";
+
+/// A function to call with status changes, see `Runner::on_status_change`.
+pub type StatusCallback = dyn Fn(&StatusEvent) + Send + Sync;
+
+/// A running job, which hands the job back along with its result.
+pub type JobHandle = JoinHandle<(Job, Result<(), Error>)>;
+
+
+/// Aborts the spawned tasks it holds when dropped, so that cancelling a job
+/// or task also cancels the work it spawned.
+#[derive(Default)]
+struct AbortOnDrop(Vec<tokio::task::AbortHandle>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        for handle in &self.0 {
+            handle.abort();
+        }
+    }
+}
+
+
+/// Which step output a job keeps in the tracker.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Capture {
+    /// Keep the output of every step
+    #[default]
+    All,
+    /// Only keep output from tasks that fail, clearing it once a task succeeds
+    OnFailure,
+}
+
+
+/// A source of the current time for status timestamps, which tests can
+/// replace to get predictable durations.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> SystemTime;
+}
+
+/// A clock that only moves when it is told to.
+pub struct ManualClock {
+    now: Mutex<SystemTime>,
+}
+
+impl ManualClock {
+    pub fn new(now: SystemTime) -> ManualClock {
+        ManualClock { now: Mutex::new(now) }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock() += duration;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> SystemTime {
+        *self.now.lock()
+    }
+}
+
+/// The real time.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+
+/// Conditions a job file must satisfy to be loaded at all, given under
+/// a top-level `bed:` key.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Conditions {
+    /// Operating systems the job applies to, as in `std::env::consts::OS`
+    #[serde(default)]
+    pub os: Vec<String>,
+    /// Environment variables that must be set
+    #[serde(default)]
+    pub env: Vec<String>,
+}
+
+impl Conditions {
+    pub fn satisfied(&self) -> bool {
+        let os = self.os.is_empty() || self.os.iter().any(|os| os == std::env::consts::OS);
+        let env = self.env.iter().all(|name| std::env::var_os(name).is_some());
+        os && env
+    }
+}
 
 
 #[derive(Debug)]
 pub enum Error {
-    CircularDependency,
+    Cancelled(String),
+    /// Jobs, tasks or steps that wait on each other, directly or not
+    CircularDependency(Vec<String>),
+    DuplicateJob(String),
+    /// Two steps in a task share a name, as `<job>/<task>/<step>`
+    DuplicateStep(String),
+    DuplicateTask(String),
+    EmptyCommand(Box<Step>),
+    /// A job's matrix has a key with no values, as `<job>.<key>`
+    EmptyMatrix(String),
+    /// An env file couldn't be read or has a malformed line
+    EnvFile(String, String),
     Exit(std::process::ExitStatus),
+    Http(reqwest::Error),
+    HttpStatus(String, u16),
+    InvalidMethod(String),
+    InvalidPattern(String),
+    InvalidReference(String),
     Io(std::io::Error),
-    JobFailed(Job),
+    JobFailed(Box<Job>),
+    JobNotReady(String),
     Join(JoinError),
+    MissingArtifact(String),
     MissingDependency(String),
+    Multiple(Vec<Error>),
+    /// The program to run couldn't be found, on `PATH` or at the given path
+    ProgramNotFound(String),
     Serde(serde_yml::Error),
-    TaskFailed(Task),
+    SerdeJson(serde_json::Error),
+    /// A command exited unsuccessfully, with the last lines it printed
+    StepFailed {
+        step: String,
+        output: Vec<String>,
+        status: std::process::ExitStatus,
+    },
+    TaskFailed(Box<Task>),
+    Timeout(String),
+    /// No job has the tag asked for
+    UnknownTag(String),
+    /// Work left waiting on dependencies that can no longer finish
+    Unsatisfiable(Vec<String>),
 }
 
 impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
-            Error::CircularDependency => write!(f, "Circular dependency detected"),
+            Error::Cancelled(name) => write!(f, "Cancelled: {}", name),
+            Error::CircularDependency(names) => {
+                write!(f, "Circular dependency between: {}", names.join(", "))
+            }
+            Error::DuplicateJob(name) => write!(f, "Duplicate job: {}", name),
+            Error::DuplicateStep(name) => write!(f, "Duplicate step: {}", name),
+            Error::DuplicateTask(name) => write!(f, "Duplicate task: {}", name),
+            Error::EmptyCommand(step) => match step.as_ref() {
+                Step::Command { name: Some(name), .. } => write!(f, "Empty command in step: {}", name),
+                _ => write!(f, "Empty command: a command step needs at least one arg"),
+            },
+            Error::EmptyMatrix(key) => write!(f, "No values for matrix key: {}", key),
+            Error::EnvFile(path, reason) => write!(f, "Invalid env file {}: {}", path, reason),
+            Error::MissingArtifact(path) => write!(f, "Missing artifact: {}", path),
             Error::MissingDependency(name) => write!(f, "Missing dependency: {}", name),
+            Error::Multiple(errors) => {
+                write!(f, "{} errors:", errors.len())?;
+                for error in errors {
+                    write!(f, "\n  {}", error)?;
+                }
+                Ok(())
+            }
+            Error::ProgramNotFound(program) => write!(f, "Program not found: {}", program),
             Error::JobFailed(job) => write!(f, "Job failed: {}", job.name),
-            Error::Join(error) => write!(f, "Join error: {}", error),
+            Error::JobNotReady(name) => write!(f, "Job not ready: {}", name),
+            Error::Join(error) => write!(f, "Background task failed: {}", error),
             Error::TaskFailed(task) => write!(f, "Task failed: {}", task.name),
-            Error::Exit(status) => write!(f, "Exit status: {}", status),
+            Error::Exit(status) => write!(f, "Command failed with {}", status),
+            Error::Http(error) => write!(f, "HTTP error: {}", error),
+            Error::HttpStatus(url, code) => write!(f, "HTTP status {} from {}", code, url),
+            Error::InvalidMethod(method) => write!(f, "Invalid HTTP method: {}", method),
+            Error::InvalidPattern(pattern) => write!(f, "Invalid glob pattern: {}", pattern),
+            Error::InvalidReference(reference) => write!(f, "Invalid reference: {}", reference),
             Error::Io(error) => write!(f, "I/O error: {}", error),
-            Error::Serde(error) => write!(f, "Serde error: {}", error),
+            Error::Serde(error) => write!(f, "Invalid pipeline definition: {}", error),
+            Error::SerdeJson(error) => write!(f, "Invalid JSON: {}", error),
+            Error::StepFailed { step, output, status } => {
+                write!(f, "Step {} failed with {}", step, status)?;
+                for line in output {
+                    write!(f, "\n  | {}", line.trim_end())?;
+                }
+                Ok(())
+            }
+            Error::Timeout(name) => write!(f, "Timed out: {}", name),
+            Error::UnknownTag(tag) => write!(f, "No jobs tagged: {}", tag),
+            Error::Unsatisfiable(names) => {
+                write!(f, "Dependencies can never be met for: {}", names.join(", "))
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Http(error) => Some(error),
+            Error::Io(error) => Some(error),
+            Error::Join(error) => Some(error),
+            Error::Serde(error) => Some(error),
+            Error::SerdeJson(error) => Some(error),
+            _ => None,
         }
     }
 }
@@ -38,12 +238,24 @@ impl From<JoinError> for Error {
     }
 }
 
+impl From<reqwest::Error> for Error {
+    fn from(error: reqwest::Error) -> Error {
+        Error::Http(error)
+    }
+}
+
 impl From<std::io::Error> for Error {
     fn from(error: std::io::Error) -> Error {
         Error::Io(error)
     }
 }
 
+impl From<serde_json::Error> for Error {
+    fn from(error: serde_json::Error) -> Error {
+        Error::SerdeJson(error)
+    }
+}
+
 impl From<serde_yml::Error> for Error {
     fn from(error: serde_yml::Error) -> Error {
         Error::Serde(error)
@@ -54,12 +266,75 @@ impl From<serde_yml::Error> for Error {
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Job {
     pub name: String,
+    /// What the job is for, shown by the API and `list`; not used to run it
+    #[serde(default)]
+    pub description: Option<String>,
+    /// Labels for picking out groups of jobs to run, e.g. `test` or `deploy`
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Run the job in a new temporary directory, given to its steps as
+    /// `BED_WORK_DIR` and removed once the job is over. Relative paths in
+    /// steps, `produces` and `artifacts` are taken from there.
+    #[serde(default)]
+    pub isolate: bool,
+    #[serde(default, rename = "bed")]
+    pub conditions: Option<Conditions>,
     #[serde(default)]
     pub depends: Vec<String>,
     pub tasks: Vec<Task>,
+    #[serde(default)]
+    pub capture: Capture,
+    /// Program and arguments to prefix every command in the job with,
+    /// overriding the runner's wrapper
+    #[serde(default)]
+    pub command_wrapper: Vec<String>,
+    /// Environment variables for every command in the job
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    /// File of `KEY=VALUE` lines to load into the environment before `env`
+    /// is applied; `#` starts a comment line
+    #[serde(default)]
+    pub env_file: Option<String>,
+    /// Carry on without the env file if it doesn't exist
+    #[serde(default)]
+    pub env_file_optional: bool,
+    /// Steps to run when the job fails, with `BED_JOB_STATUS=failed` and the
+    /// error in `BED_JOB_ERROR`
+    #[serde(default)]
+    pub on_failure: Vec<Step>,
+    /// Steps to run after the job's tasks and any `on_failure` steps, however
+    /// the job ended: finished, failed, timed out or cancelled. A cancelled or
+    /// timed out job's tasks are stopped first, then its hooks run to the end.
+    /// `BED_JOB_STATUS` is set to `finished`, `failed` or `cancelled`.
+    #[serde(default)]
+    pub always: Vec<Step>,
+    /// Files or directories the job produces, which must exist once its
+    /// tasks succeed; copied into the runner's artifact directory if it has
+    /// one
+    #[serde(default)]
+    pub artifacts: Vec<String>,
+    /// Files of tasks shared between jobs, added ahead of the job's own
+    /// tasks at load time. Each holds `tasks` and may `include` more files;
+    /// paths are relative to the file that names them. Keep them out of the
+    /// pipeline directory itself, e.g. in a subdirectory, so they aren't
+    /// loaded as jobs.
+    #[serde(default)]
+    pub include: Vec<String>,
+    /// Values to run the job with, expanded at load time into one job per
+    /// combination named `<name>[<value>,...]` in key order. Steps refer to
+    /// the values as `${matrix.<key>}`.
+    #[serde(default)]
+    pub matrix: HashMap<String, Vec<String>>,
+    #[serde(default)]
+    pub timeout_secs: Option<u64>,
 }
 
 impl Job {
+    /// Start building a job with the given name.
+    pub fn builder(name: impl Into<String>) -> JobBuilder {
+        JobBuilder { job: Job::new(name.into()) }
+    }
+
     pub fn depends(&mut self, name: String) {
         self.depends.push(name);
     }
@@ -67,525 +342,4547 @@ impl Job {
     pub fn new(name: String) -> Job {
         Job {
             name,
+            description: None,
+            tags: Vec::new(),
+            isolate: false,
+            conditions: None,
             depends: Vec::new(),
             tasks: Vec::new(),
+            capture: Capture::All,
+            command_wrapper: Vec::new(),
+            env: HashMap::new(),
+            env_file: None,
+            env_file_optional: false,
+            matrix: HashMap::new(),
+            on_failure: Vec::new(),
+            always: Vec::new(),
+            artifacts: Vec::new(),
+            include: Vec::new(),
+            timeout_secs: None,
+        }
+    }
+
+    /// Expand the job's matrix into one job per combination of values, or
+    /// return the job as is if it has no matrix. A key without any values is
+    /// an error, rather than quietly leaving no jobs.
+    pub fn expand(&self) -> Result<Vec<Job>, Error> {
+        if self.matrix.is_empty() {
+            return Ok(vec![self.clone()]);
+        }
+
+        let mut keys: Vec<&String> = self.matrix.keys().collect();
+        keys.sort();
+        if let Some(key) = keys.iter().find(|key| self.matrix[**key].is_empty()) {
+            return Err(Error::EmptyMatrix(format!("{}.{}", self.name, key)));
+        }
+        let mut combinations: Vec<Vec<&String>> = vec![Vec::new()];
+        for key in &keys {
+            combinations = combinations.into_iter()
+                .flat_map(|combination| {
+                    self.matrix[*key].iter().map(move |value| {
+                        let mut combination = combination.clone();
+                        combination.push(value);
+                        combination
+                    })
+                })
+                .collect();
+        }
+
+        let mut jobs = Vec::new();
+        for values in combinations {
+            let mut job = self.clone();
+            job.matrix.clear();
+            let names: Vec<&str> = values.iter().map(|value| value.as_str()).collect();
+            job.name = format!("{}[{}]", self.name, names.join(","));
+
+            let substitute = |text: &str| {
+                let mut text = text.to_string();
+                for (key, value) in keys.iter().zip(&values) {
+                    text = text.replace(&format!("${{matrix.{}}}", key), value);
+                }
+                text
+            };
+            for value in job.env.values_mut().chain(&mut job.env_file) {
+                *value = substitute(value);
+            }
+            for task in &mut job.tasks {
+                for value in task.env.values_mut() {
+                    *value = substitute(value);
+                }
+                for step in &mut task.steps {
+                    step.substitute(|text| Ok(substitute(text)))?;
+                }
+            }
+            for step in job.on_failure.iter_mut().chain(&mut job.always) {
+                step.substitute(|text| Ok(substitute(text)))?;
+            }
+            jobs.push(job);
+        }
+        Ok(jobs)
+    }
+
+    /// Tasks that can never start because they wait on each other.
+    fn task_cycle(&self) -> Vec<String> {
+        let graph: Vec<(String, Vec<String>)> = self.tasks.iter()
+            .map(|task| {
+                let depends = task.depends.iter().map(String::as_str).chain(task.producer());
+                (task.name.clone(), depends.map(String::from).collect())
+            })
+            .collect();
+        unorderable(&graph).into_iter().map(|name| format!("{}/{}", self.name, name)).collect()
+    }
+
+    pub fn ready(&self, finished: &[Job]) -> bool {
+        self.waiting_on(finished).is_empty()
+    }
+
+    /// The jobs this one depends on that aren't in `finished` yet.
+    pub fn waiting_on(&self, finished: &[Job]) -> Vec<&str> {
+        self.depends.iter()
+            .filter(|name| !finished.iter().any(|job| job.name == **name))
+            .map(String::as_str)
+            .collect()
+    }
+
+    /// Run the job's tasks, then its hooks. If `stop` completes first, the
+    /// tasks are stopped and its error becomes the job's result, but the
+    /// hooks still run.
+    pub async fn run(&mut self, mut tracker: TaskTracker, stop: impl Future<Output = Error>) -> Result<(), Error> {
+        if !self.command_wrapper.is_empty() {
+            tracker.command_wrapper(self.command_wrapper.clone());
+        }
+        if let Some(path) = &self.env_file {
+            tracker.env.extend(read_env_file(path, self.env_file_optional).await?);
+        }
+        tracker.env.extend(self.env.clone());
+        // Held until the job is over, and removed when dropped, however the
+        // job ends
+        let work_dir = if self.isolate {
+            Some(tempfile::Builder::new().prefix("bed-").tempdir()?)
+        } else {
+            None
+        };
+        if let Some(dir) = &work_dir {
+            let path = dir.path().to_string_lossy().into_owned();
+            tracker.env.insert("BED_WORK_DIR".to_string(), path);
+            tracker.working_dir = Some(dir.path().to_path_buf());
+        }
+
+        let result = match self.run_tasks(tracker.clone(), stop).await {
+            Ok(()) => self.collect_artifacts(&tracker).await,
+            Err(e) => Err(e),
+        };
+        let failed = result.is_err() && !matches!(result, Err(Error::Cancelled(_)));
+        if failed && !self.on_failure.is_empty() {
+            Job::run_hook("on_failure", &self.on_failure, &result, &tracker).await;
+        }
+        if !self.always.is_empty() {
+            Job::run_hook("always", &self.always, &result, &tracker).await;
+        }
+        result
+    }
+
+    /// Check the job's artifacts exist, copy them to `<artifact dir>/<job>/`
+    /// keeping their relative paths, and record where they ended up.
+    async fn collect_artifacts(&self, tracker: &TaskTracker) -> Result<(), Error> {
+        let mut collected = Vec::new();
+        for artifact in &self.artifacts {
+            let path = tracker.resolve(artifact);
+            if tokio::fs::metadata(&path).await.is_err() {
+                return Err(Error::MissingArtifact(format!("{}: {}", self.name, artifact)));
+            }
+            let Some(dir) = &tracker.artifact_dir else {
+                collected.push(path.to_string_lossy().into_owned());
+                continue;
+            };
+            // Only the normal parts of the path, so nothing lands outside
+            // the job's directory
+            let relative: PathBuf = std::path::Path::new(artifact).components()
+                .filter(|component| matches!(component, std::path::Component::Normal(_)))
+                .collect();
+            let destination = dir.join(&self.name).join(relative);
+            let target = destination.clone();
+            tokio::task::spawn_blocking(move || copy_path(&path, &target)).await??;
+            collected.push(destination.to_string_lossy().into_owned());
         }
+        if !collected.is_empty() {
+            tracker.job_tracker.modify(&self.name, |job| job.artifacts = collected);
+        }
+        Ok(())
     }
 
-    pub fn ready(&self, finished: &Vec<Job>) -> bool {
-        self.depends.iter().all(|name| finished.iter().any(|job| job.name == *name))
+    /// Run a hook's steps, tracked alongside the job's tasks. A failing hook
+    /// is logged rather than returned, so it can't mask the job's own result.
+    async fn run_hook(
+        name: &str,
+        steps: &[Step],
+        result: &Result<(), Error>,
+        tracker: &TaskTracker,
+    ) {
+        // Let the hook know how the job went
+        let mut hook = Task::new(name.to_string());
+        hook.steps = steps.to_vec();
+        let status = match result {
+            Ok(()) => "finished",
+            Err(Error::Cancelled(_)) => "cancelled",
+            Err(e) => {
+                hook.env.insert("BED_JOB_ERROR".to_string(), e.to_string());
+                "failed"
+            }
+        };
+        hook.env.insert("BED_JOB_STATUS".to_string(), status.to_string());
+
+        tracker.insert_hook(TaskStatus::new(&hook));
+        tracker.modify(&hook.name, |task| {
+            task.set_status(Status::Running, tracker.now());
+        });
+        let result = hook.run_steps(StepTracker::new(hook.name.clone(), tracker.clone())).await;
+        tracker.modify(&hook.name, |task| {
+            let status = match result {
+                Ok(()) => Status::Finished,
+                Err(_) => Status::Failed,
+            };
+            task.set_status(status, tracker.now());
+        });
+        if let Err(e) = result {
+            tracing::warn!(job = %tracker.job_name, hook = %hook.name, error = %e, "Hook failed");
+        }
     }
 
-    pub async fn run(&mut self, tracker: TaskTracker) -> Result<(), Error> {
+    async fn run_tasks(&mut self, tracker: TaskTracker, stop: impl Future<Output = Error>) -> Result<(), Error> {
         // Check if all dependencies are available
         for task in &self.tasks {
-            for name in &task.depends {
-                if !self.tasks.iter().any(|task| task.name == *name) {
+            if let (Some(for_each), None) = (&task.for_each, task.producer()) {
+                let reference = format!("{}/{}: {}", self.name, task.name, for_each);
+                return Err(Error::InvalidReference(reference));
+            }
+            for name in task.depends.iter().map(String::as_str).chain(task.producer()) {
+                if !self.tasks.iter().any(|task| task.name == name) {
                     let name = format!("{}/{}", self.name, name);
                     return Err(Error::MissingDependency(name));
                 }
             }
         }
+        let cycle = self.task_cycle();
+        if !cycle.is_empty() {
+            return Err(Error::CircularDependency(cycle));
+        }
 
-        let mut pending = self.tasks.clone();
+        let capture = self.capture;
+        let producers: Vec<String> = self.tasks.iter()
+            .filter_map(|task| task.producer().map(String::from))
+            .collect();
+        let deadline = self.timeout_secs.map(|secs| Instant::now() + Duration::from_secs(secs));
+        // Move the tasks out to run them rather than cloning each one. Each
+        // one hands itself back when it's done or halted, so they can all be
+        // put back once the job is over.
+        let names: Vec<String> = self.tasks.iter().map(|task| task.name.clone()).collect();
+        let mut pending: Vec<Option<Task>> = std::mem::take(&mut self.tasks).into_iter().map(Some).collect();
         let mut running = Vec::new();
         let mut finished = Vec::new();
+        let mut spawned = AbortOnDrop::default();
+        let mut stop = std::pin::pin!(stop);
+        let (halt, halted) = tokio::sync::watch::channel(false);
+        let mut timed_out = false;
 
-        loop {
-            // Filter out tasks that are ready to run
-            pending.retain(|task| {
-                // Check if the task is ready to run
-                if task.ready(&finished) {
-                    // Clone to avoid borrowing issues
-                    let mut task = task.clone();
+        let result = loop {
+            // Spawn the tasks that are ready to run
+            for slot in &mut pending {
+                if let Some(mut task) = slot.take_if(|task| task.ready(&finished)) {
+                    tracing::debug!(target: "bed::scheduler", job = %self.name, task = %task.name, "Task ready");
                     let task_name = task.name.clone();
                     let task_name2 = task.name.clone();
                     let task_name3 = task.name.clone();
-                    let tracker_clone = tracker.clone();
+                    let mut step_tracker = StepTracker::new(task_name, tracker.clone());
+                    step_tracker.collect_items = producers.contains(&task.name);
                     let tracker_clone2 = tracker.clone();
+                    let mut halted = halted.clone();
                     // Spawn the task to run asynchronously
-                    running.push(tokio::spawn(async move {
-                        match task.run(StepTracker::new(task_name, tracker_clone)).await {
+                    let handle = tokio::spawn(async move {
+                        let result = tokio::select! {
+                            result = task.run(step_tracker) => result,
+                            // The job records the status of a halted task
+                            _ = halted.wait_for(|halted| *halted) => {
+                                return (task, Err(Error::Cancelled(task_name2)));
+                            }
+                        };
+                        match result {
                             Ok(()) => {
                                 tracker_clone2.modify(&task_name2, |task| {
-                                    task.status = Status::Finished;
+                                    task.set_status(Status::Finished, tracker_clone2.now());
+                                    if capture == Capture::OnFailure {
+                                        // A step allowed to fail still failed, so it keeps its output
+                                        for step in &mut task.steps {
+                                            if let StepStatus::Command { output, status, .. }
+                                                | StepStatus::Script { output, status, .. } = step
+                                            {
+                                                if *status != Status::Failed {
+                                                    output.clear();
+                                                }
+                                            }
+                                        }
+                                    }
                                 });
-                                Ok(task)
+                                (task, Ok(()))
                             }
                             Err(e) => {
                                 tracker_clone2.modify(&task_name2, |task| {
-                                    task.status = Status::Failed;
+                                    task.set_status(Status::Failed, tracker_clone2.now());
                                 });
-                                Err(e)
+                                (task, Err(e))
                             }
                         }
-                    }));
+                    });
+                    spawned.0.push(handle.abort_handle());
+                    running.push(handle);
                     // Update the task status
                     tracker.modify(&task_name3, |task| {
-                        task.status = Status::Running;
+                        task.set_status(Status::Running, tracker.now());
                     });
-                    // Remove the task from the pending list
-                    false
-                } else {
-                    // Keep the task in the pending list
-                    true
                 }
-            });
-
+            }
+            pending.retain(Option::is_some);
+            for task in pending.iter().flatten() {
+                let waiting_on = task.waiting_on(&finished);
+                tracing::debug!(
+                    target: "bed::scheduler",
+                    job = %self.name,
+                    task = %task.name,
+                    ?waiting_on,
+                    "Task waiting",
+                );
+            }
 
             if !running.is_empty() {
-                // Wait for any task to finish
-                let (done, _, rest) = futures::future::select_all(running).await;
-                // Update the running list
-                running = rest;
+                // Wait for any task to finish, unless the job is stopped first
+                let done = tokio::select! {
+                    done = select_running(&mut running, deadline) => done,
+                    error = &mut stop => {
+                        timed_out = matches!(error, Error::Timeout(_));
+                        break Err(error);
+                    }
+                };
+                let Some(done) = done else {
+                    timed_out = true;
+                    break Err(Error::Timeout(self.name.clone()));
+                };
                 // Match the result of the task
                 match done {
-                    Ok(Ok(task)) => {
+                    Ok((task, Ok(()))) => {
                         // Add the task to the finished list
                         finished.push(task);
                     }
-                    Ok(Err(e)) => {
-                        return Err(e);
+                    Ok((task, Err(e))) => {
+                        pending.push(Some(task));
+                        break Err(e);
                     }
                     Err(e) => {
-                        return Err(Error::Join(e));
+                        break Err(Error::Join(e));
                     }
                 }
             } else if pending.is_empty() && running.is_empty() {
-                self.tasks = finished;
-                return Ok(());
+                break Ok(());
             } else if running.is_empty() {
-                return Err(Error::CircularDependency);
+                let names = pending.iter().flatten()
+                    .map(|task| format!("{}/{}", self.name, task.name))
+                    .collect();
+                break Err(Error::Unsatisfiable(names));
+            }
+        };
+
+        // Halt whatever is still running when the job stops early, and mark
+        // it failed if the job ran out of time or cancelled otherwise
+        let mut stopped = Vec::new();
+        if !running.is_empty() {
+            halt.send_replace(true);
+            for (task, _) in futures::future::join_all(running).await.into_iter().flatten() {
+                stopped.push(task);
+            }
+            let status = if timed_out { Status::Failed } else { Status::Cancelled };
+            for name in &names {
+                tracker.modify(name, |task| task.interrupt(status.clone(), tracker.now()));
             }
         }
+
+        // Put the tasks back in the order they were written
+        let mut tasks: Vec<Task> = finished.into_iter()
+            .chain(stopped)
+            .chain(pending.into_iter().flatten())
+            .collect();
+        tasks.sort_by_key(|task| names.iter().position(|name| *name == task.name));
+        self.tasks = tasks;
+        result
+    }
+}
+
+
+/// Builds a `Job` in code, e.g.
+/// `Job::builder("build").depends("fetch").task(Task::builder("compile").build()).build()`.
+pub struct JobBuilder {
+    job: Job,
+}
+
+impl JobBuilder {
+    pub fn always(mut self, step: Step) -> JobBuilder {
+        self.job.always.push(step);
+        self
+    }
+
+    pub fn build(self) -> Job {
+        self.job
+    }
+
+    pub fn artifact(mut self, path: impl Into<String>) -> JobBuilder {
+        self.job.artifacts.push(path.into());
+        self
+    }
+
+    pub fn capture(mut self, capture: Capture) -> JobBuilder {
+        self.job.capture = capture;
+        self
+    }
+
+    pub fn command_wrapper(mut self, command_wrapper: Vec<String>) -> JobBuilder {
+        self.job.command_wrapper = command_wrapper;
+        self
+    }
+
+    pub fn depends(mut self, name: impl Into<String>) -> JobBuilder {
+        self.job.depends.push(name.into());
+        self
+    }
+
+    pub fn description(mut self, description: impl Into<String>) -> JobBuilder {
+        self.job.description = Some(description.into());
+        self
+    }
+
+    pub fn env(mut self, key: impl Into<String>, value: impl Into<String>) -> JobBuilder {
+        self.job.env.insert(key.into(), value.into());
+        self
+    }
+
+    pub fn env_file(mut self, path: impl Into<String>) -> JobBuilder {
+        self.job.env_file = Some(path.into());
+        self
+    }
+
+    pub fn isolate(mut self, isolate: bool) -> JobBuilder {
+        self.job.isolate = isolate;
+        self
+    }
+
+    pub fn on_failure(mut self, step: Step) -> JobBuilder {
+        self.job.on_failure.push(step);
+        self
+    }
+
+    pub fn tag(mut self, tag: impl Into<String>) -> JobBuilder {
+        self.job.tags.push(tag.into());
+        self
+    }
+
+    pub fn task(mut self, task: Task) -> JobBuilder {
+        self.job.tasks.push(task);
+        self
+    }
+
+    pub fn timeout_secs(mut self, timeout_secs: u64) -> JobBuilder {
+        self.job.timeout_secs = Some(timeout_secs);
+        self
     }
 }
 
 
 #[derive(Clone)]
 pub struct JobTracker {
+    /// Called with every change made through this handle to the tracker
+    callbacks: Vec<Arc<StatusCallback>>,
+    /// Signals to stop each running job, by name
+    cancels: Arc<Mutex<HashMap<String, Arc<Notify>>>>,
+    clock: Arc<dyn Clock>,
     jobs: Arc<Mutex<HashMap<String, JobStatus>>>,
+    /// Id of the run being tracked, set by a `RunStore`, or 0 outside one
+    run_id: Arc<AtomicU64>,
+    updates: broadcast::Sender<StatusEvent>,
+}
+
+impl Default for JobTracker {
+    fn default() -> JobTracker {
+        JobTracker::new()
+    }
 }
 
 impl JobTracker {
     pub fn new() -> JobTracker {
+        JobTracker::with_clock(Arc::new(SystemClock))
+    }
+
+    /// A tracker that timestamps status changes with the given clock.
+    pub fn with_clock(clock: Arc<dyn Clock>) -> JobTracker {
+        let (updates, _) = broadcast::channel(1024);
         JobTracker {
+            callbacks: Vec::new(),
+            cancels: Arc::new(Mutex::new(HashMap::new())),
+            clock,
             jobs: Arc::new(Mutex::new(HashMap::new())),
+            run_id: Arc::new(AtomicU64::new(0)),
+            updates,
         }
     }
 
+    /// Id of the run being tracked, when a `RunStore` keeps the runs.
+    pub fn run_id(&self) -> Option<u64> {
+        match self.run_id.load(Ordering::Relaxed) {
+            0 => None,
+            id => Some(id),
+        }
+    }
+
+    pub fn now(&self) -> SystemTime {
+        self.clock.now()
+    }
+
     pub fn get(&self, name: &str) -> Option<JobStatus> {
-        self.jobs.lock().unwrap().get(name).cloned()
+        self.jobs.lock().get(name).cloned()
+    }
+
+    /// Look at a job's status in place, without copying it out.
+    pub fn inspect<F, T>(&self, name: &str, f: F) -> Option<T>
+    where
+        F: FnOnce(&JobStatus) -> T,
+    {
+        self.jobs.lock().get(name).map(f)
+    }
+
+    /// Stop a running job, leaving the rest of the run going. Returns false
+    /// if the job isn't running.
+    pub fn cancel(&self, name: &str) -> bool {
+        match self.cancels.lock().get(name) {
+            Some(cancel) => {
+                cancel.notify_one();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Forget every job's status, ahead of starting a new run.
+    pub fn clear(&self) {
+        self.cancels.lock().clear();
+        self.jobs.lock().clear();
+    }
+
+    /// Every job's status, ordered by name.
+    pub fn list(&self) -> Vec<JobStatus> {
+        let mut jobs: Vec<JobStatus> = self.jobs.lock().values().cloned().collect();
+        jobs.sort_by(|a, b| a.name.cmp(&b.name));
+        jobs
     }
 
-    pub fn insert(&self, job: JobStatus) {
-        self.jobs.lock().unwrap().insert(job.name.clone(), job);
+    pub fn insert(&self, mut job: JobStatus) {
+        job.update_progress();
+        let events = self.changes(&[], &job);
+        self.jobs.lock().insert(job.name.clone(), job);
+        self.publish(events);
     }
 
     pub fn modify<F>(&self, name: &str, f: F)
     where
         F: FnOnce(&mut JobStatus),
     {
-        let mut jobs = self.jobs.lock().unwrap();
-        if let Some(job) = jobs.get_mut(name) {
-            f(job);
+        let mut jobs = self.jobs.lock();
+        let Some(job) = jobs.get_mut(name) else {
+            return;
+        };
+        // Skip working out what changed when nobody is listening
+        let before = if self.listening() { job.events() } else { Vec::new() };
+        f(job);
+        job.update_progress();
+        let events = self.changes(&before, job);
+        // Let go of the lock first, so callbacks can use the tracker
+        drop(jobs);
+        self.publish(events);
+    }
+
+    /// A handle to the same tracker that also calls `callback` with every
+    /// status change made through it, after the change is made.
+    pub fn on_status_change(&self, callback: Arc<StatusCallback>) -> JobTracker {
+        let mut tracker = self.clone();
+        tracker.callbacks.push(callback);
+        tracker
+    }
+
+    /// Receive an event for every status change of a job, task or step.
+    pub fn subscribe(&self) -> broadcast::Receiver<StatusEvent> {
+        self.updates.subscribe()
+    }
+
+    fn listening(&self) -> bool {
+        self.updates.receiver_count() > 0 || !self.callbacks.is_empty()
+    }
+
+    fn changes(&self, before: &[StatusEvent], job: &JobStatus) -> Vec<StatusEvent> {
+        if !self.listening() {
+            return Vec::new();
         }
+        job.events().into_iter().filter(|event| !before.contains(event)).collect()
     }
-}
 
+    fn publish(&self, events: Vec<StatusEvent>) {
+        for event in events {
+            for callback in &self.callbacks {
+                callback(&event);
+            }
+            let _ = self.updates.send(event);
+        }
+    }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
-pub struct JobStatus {
-    pub name: String,
-    #[serde(default)]
-    pub depends: Vec<String>,
-    pub tasks: Vec<TaskStatus>,
-    #[serde(default)]
-    pub status: Status,
-}
+    /// Count the jobs in each status.
+    pub fn summary(&self) -> StatusSummary {
+        StatusSummary::new(&self.list())
+    }
 
+    /// Render the current statuses in the Prometheus text exposition format.
+    pub fn to_metrics(&self) -> String {
+        const BUCKETS: [f64; 9] = [0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0, 600.0];
+        const STATUSES: [Status; 6] = [
+            Status::Pending,
+            Status::Running,
+            Status::Finished,
+            Status::Failed,
+            Status::Skipped,
+            Status::Cancelled,
+        ];
+        let label = |status: &Status| format!("{:?}", status).to_lowercase();
+        let jobs = self.list();
+        let tasks: Vec<&TaskStatus> = jobs.iter().flat_map(|job| &job.tasks).collect();
+        let steps: Vec<&StepStatus> = tasks.iter().flat_map(|task| &task.steps).collect();
+        let mut text = String::new();
 
-pub struct Loader {
-    pub directory: String,
-    pub jobs: Vec<Job>,
-}
+        text.push_str("# HELP bed_jobs Jobs in the current run by status.\n# TYPE bed_jobs gauge\n");
+        for status in &STATUSES {
+            let count = jobs.iter().filter(|job| job.status == *status).count();
+            text.push_str(&format!("bed_jobs{{status=\"{}\"}} {}\n", label(status), count));
+        }
+        text.push_str("# HELP bed_tasks Tasks in the current run by status.\n# TYPE bed_tasks gauge\n");
+        for status in &STATUSES {
+            let count = tasks.iter().filter(|task| task.status == *status).count();
+            text.push_str(&format!("bed_tasks{{status=\"{}\"}} {}\n", label(status), count));
+        }
+        text.push_str("# HELP bed_steps Steps in the current run by status.\n# TYPE bed_steps gauge\n");
+        for status in &STATUSES {
+            let count = steps.iter().filter(|step| step.status() == status).count();
+            text.push_str(&format!("bed_steps{{status=\"{}\"}} {}\n", label(status), count));
+        }
 
-impl Loader {
-    pub fn new(directory: String) -> Loader {
-        Loader {
-            directory,
-            jobs: Vec::new(),
+        text.push_str("# HELP bed_job_duration_seconds How long each completed job took.\n");
+        text.push_str("# TYPE bed_job_duration_seconds gauge\n");
+        for job in &jobs {
+            if let Some(duration_ms) = job.duration_ms {
+                text.push_str(&format!(
+                    "bed_job_duration_seconds{{job=\"{}\",status=\"{}\"}} {:.3}\n",
+                    escape_label(&job.name),
+                    label(&job.status),
+                    duration_ms as f64 / 1000.0,
+                ));
+            }
         }
+
+        text.push_str("# HELP bed_step_duration_seconds How long completed steps took.\n");
+        text.push_str("# TYPE bed_step_duration_seconds histogram\n");
+        let durations: Vec<f64> = steps.iter()
+            .filter_map(|step| step.duration_ms())
+            .map(|duration_ms| duration_ms as f64 / 1000.0)
+            .collect();
+        for bucket in BUCKETS {
+            let count = durations.iter().filter(|&&seconds| seconds <= bucket).count();
+            text.push_str(&format!("bed_step_duration_seconds_bucket{{le=\"{}\"}} {}\n", bucket, count));
+        }
+        text.push_str(&format!("bed_step_duration_seconds_bucket{{le=\"+Inf\"}} {}\n", durations.len()));
+        text.push_str(&format!("bed_step_duration_seconds_sum {:.3}\n", durations.iter().sum::<f64>()));
+        text.push_str(&format!("bed_step_duration_seconds_count {}\n", durations.len()));
+
+        text
     }
 
-    pub fn load(&mut self) -> Result<(), Error> {
-        let entries = std::fs::read_dir(&self.directory)?;
+    /// Report the run as JUnit XML, with a test suite per job and a test
+    /// case per task. Failed tasks carry the output of their failed steps.
+    pub fn to_junit_xml(&self) -> String {
+        let seconds = |duration_ms: Option<u64>| duration_ms.unwrap_or(0) as f64 / 1000.0;
+        let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<testsuites>\n");
 
-        for entry in entries {
-            match entry {
-                Ok(entry) => {
-                    let path = entry.path();
-                    if path.is_file() {
-                        match path.extension() {
-                            Some(ext) => {
-                                if ext == "yml" || ext == "yaml" {
-                                    self.load_file(path)?;
+        for job in self.list() {
+            let count = |status: Status| job.tasks.iter().filter(|task| task.status == status).count();
+            xml.push_str(&format!(
+                "  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" skipped=\"{}\" time=\"{:.3}\">\n",
+                escape_xml(&job.name),
+                job.tasks.len(),
+                count(Status::Failed),
+                count(Status::Skipped) + count(Status::Pending) + count(Status::Cancelled),
+                seconds(job.duration_ms),
+            ));
+
+            for task in &job.tasks {
+                xml.push_str(&format!(
+                    "    <testcase classname=\"{}\" name=\"{}\" time=\"{:.3}\"",
+                    escape_xml(&job.name),
+                    escape_xml(&task.name),
+                    seconds(task.duration_ms),
+                ));
+                match task.status {
+                    Status::Failed => {
+                        let output: String = task.steps.iter()
+                            .filter_map(|step| match step {
+                                StepStatus::Command { output, status: Status::Failed, .. }
+                                | StepStatus::Script { output, status: Status::Failed, .. } => {
+                                    Some(output.concat())
                                 }
-                            }
-                            _ => {}
-                        }
+                                _ => None,
+                            })
+                            .collect();
+                        xml.push_str(&format!(
+                            ">\n      <failure message=\"Task failed\">{}</failure>\n    </testcase>\n",
+                            escape_xml(&output),
+                        ));
                     }
-                }
-                Err(e) => {
-                    return Err(Error::Io(e));
+                    Status::Pending | Status::Skipped | Status::Cancelled => {
+                        xml.push_str(">\n      <skipped/>\n    </testcase>\n");
+                    }
+                    Status::Running | Status::Finished => xml.push_str("/>\n"),
                 }
             }
+
+            xml.push_str("  </testsuite>\n");
         }
 
-        Ok(())
+        xml.push_str("</testsuites>\n");
+        xml
     }
 
-    pub fn load_file(&mut self, path: std::path::PathBuf) -> Result<(), Error> {
-        let file = std::fs::File::open(&path)?;
-        let job = serde_yml::from_reader(file)?;
-        self.jobs.push(job);
-        Ok(())
-    }
+    /// Render the run as a Markdown table of each job and task with its
+    /// status and duration, under the one-line summary.
+    pub fn to_markdown(&self) -> String {
+        let label = |status: &Status| format!("{:?}", status).to_lowercase();
+        let duration = |duration_ms: Option<u64>| match duration_ms {
+            Some(ms) => format!("{}.{:03}s", ms / 1000, ms % 1000),
+            None => "-".to_string(),
+        };
+        let jobs = self.list();
+        let mut markdown = format!("**bed**: {}\n\n", StatusSummary::new(&jobs));
 
-    pub fn runner(&self) -> Runner {
-        let mut runner = Runner::new();
-        runner.jobs = self.jobs.clone();
-        runner
+        markdown.push_str("| Job | Task | Status | Duration |\n| --- | --- | --- | --- |\n");
+        for job in &jobs {
+            let name = escape_markdown(&job.name);
+            markdown.push_str(&format!(
+                "| {} | | {} | {} |\n",
+                name,
+                label(&job.status),
+                duration(job.duration_ms),
+            ));
+            for task in &job.tasks {
+                markdown.push_str(&format!(
+                    "| {} | {} | {} | {} |\n",
+                    name,
+                    escape_markdown(&task.name),
+                    label(&task.status),
+                    duration(task.duration_ms),
+                ));
+            }
+        }
+        markdown
     }
 }
 
 
-pub struct Runner {
-    pub jobs: Vec<Job>,
-}
-
-impl Runner {
-    pub fn new() -> Runner {
-        Runner {
-            jobs: Vec::new(),
-        }
-    }
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct JobStatus {
+    pub name: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub depends: Vec<String>,
+    pub tasks: Vec<TaskStatus>,
+    /// Hooks that have run, such as `on_failure`
+    #[serde(default)]
+    pub hooks: Vec<TaskStatus>,
+    /// Where the job's artifacts were collected to once it succeeded
+    #[serde(default)]
+    pub artifacts: Vec<String>,
+    /// Values set by printing `::output::<key>=<value>`, for dependent jobs
+    /// to use as `${needs.<job>.outputs.<key>}`; secrets in them are masked
+    #[serde(default)]
+    pub outputs: HashMap<String, String>,
+    #[serde(default)]
+    pub status: Status,
+    /// Fraction of the tasks that are done, from 0.0 to 1.0
+    #[serde(default)]
+    pub progress: f32,
+    /// Why the job was skipped
+    #[serde(default)]
+    pub reason: Option<String>,
+    #[serde(default, with = "humantime_serde")]
+    pub started_at: Option<SystemTime>,
+    #[serde(default, with = "humantime_serde")]
+    pub finished_at: Option<SystemTime>,
+    #[serde(default)]
+    pub duration_ms: Option<u64>,
+}
 
-    pub async fn run(&mut self, tracker: JobTracker) -> Result<(), Error> {
-        for job in &self.jobs {
-            // Check if all dependencies are available
-            for name in &job.depends {
-                if !self.jobs.iter().any(|job| job.name == *name) {
-                    return Err(Error::MissingDependency(name.clone()));
-                }
+impl JobStatus {
+    /// The current status of the job and each of its tasks and steps.
+    fn events(&self) -> Vec<StatusEvent> {
+        let mut events = vec![StatusEvent {
+            job: self.name.clone(),
+            task: None,
+            step: None,
+            status: self.status.clone(),
+        }];
+        for task in self.tasks.iter().chain(&self.hooks) {
+            events.push(StatusEvent {
+                job: self.name.clone(),
+                task: Some(task.name.clone()),
+                step: None,
+                status: task.status.clone(),
+            });
+            for step in &task.steps {
+                events.push(StatusEvent {
+                    job: self.name.clone(),
+                    task: Some(task.name.clone()),
+                    step: Some(step.index()),
+                    status: step.status().clone(),
+                });
             }
+        }
+        events
+    }
 
-            // Create a job status
-            tracker.insert(JobStatus {
-                name: job.name.clone(),
-                depends: job.depends.clone(),
-                tasks: job.tasks.iter().map(|task| TaskStatus {
-                    name: task.name.clone(),
-                    depends: task.depends.clone(),
-                    steps: task.steps.iter().map(|step| match step {
-                        Step::Command { args } => StepStatus::Command {
-                            args: args.clone(),
-                            output: Vec::new(),
-                            status: Status::Pending,
-                        },
-                    }).collect(),
-                    status: Status::Pending,
-                }).collect(),
-                status: Status::Pending,
-            });
+    pub fn new(job: &Job) -> JobStatus {
+        JobStatus {
+            name: job.name.clone(),
+            description: job.description.clone(),
+            tags: job.tags.clone(),
+            depends: job.depends.clone(),
+            tasks: job.tasks.iter().map(TaskStatus::new).collect(),
+            hooks: Vec::new(),
+            artifacts: Vec::new(),
+            outputs: HashMap::new(),
+            status: Status::Pending,
+            progress: 0.0,
+            reason: None,
+            started_at: None,
+            finished_at: None,
+            duration_ms: None,
         }
+    }
 
-        let mut pending = self.jobs.clone();
-        let mut running = Vec::new();
-        let mut finished = Vec::new();
+    pub fn set_status(&mut self, status: Status, now: SystemTime) {
+        status.stamp(now, &mut self.started_at, &mut self.finished_at, &mut self.duration_ms);
+        self.status = status;
+    }
 
-        loop {
-            // Filter out jobs that are ready to run
-            pending.retain(|job| {
-                // Check if the job is ready to run
-                if job.ready(&finished) {
-                    // Clone to avoid borrowing issues
-                    let mut job = job.clone();
-                    let job_name = job.name.clone();
-                    let job_name2 = job.name.clone();
-                    let job_name3 = job.name.clone();
-                    let tracker_clone = tracker.clone();
-                    let tracker_clone2 = tracker.clone();
-                    // Spawn the job to run asynchronously
-                    running.push(tokio::spawn(async move {
-                        match job.run(TaskTracker::new(job_name, tracker_clone)).await {
-                            Ok(()) => {
-                                tracker_clone2.modify(&job_name2, |job| {
-                                    job.status = Status::Finished;
-                                });
-                                Ok(job)
-                            }
-                            Err(e) => {
-                                tracker_clone2.modify(&job_name2, |job| {
-                                    job.status = Status::Failed;
-                                });
-                                Err(e)
-                            }
-                        }
-                    }));
-                    // Update the job status
-                    tracker.modify(&job_name3, |job| {
-                        job.status = Status::Running;
-                    });
-                    // Remove the job from the pending list
-                    false
-                } else {
-                    // Keep the job in the pending list
-                    true
-                }
-            });
+    /// Put the job back to how it was before it ran, clearing the output of
+    /// its steps but keeping their attempt counts, ready to run it again.
+    pub fn reset(&mut self) {
+        // Drop the per-item instances of fanned-out tasks, which the next
+        // run recreates
+        let names: Vec<String> = self.tasks.iter().map(|task| format!("{}[", task.name)).collect();
+        self.tasks.retain(|task| {
+            !names.iter().any(|prefix| task.name.starts_with(prefix.as_str()) && task.name.ends_with(']'))
+        });
+        for task in &mut self.tasks {
+            task.reset();
+        }
+        self.hooks.clear();
+        self.artifacts.clear();
+        self.outputs.clear();
+        self.status = Status::Pending;
+        self.progress = 0.0;
+        self.reason = None;
+        self.started_at = None;
+        self.finished_at = None;
+        self.duration_ms = None;
+    }
 
-            if !running.is_empty() {
-                // Wait for any job to finish
-                let (done, _, rest) = futures::future::select_all(running).await;
-                // Update the running list
-                running = rest;
-                // Match the result of the job
-                match done {
-                    Ok(Ok(job)) => {
-                        // Add the job to the finished list
-                        finished.push(job);
-                    }
-                    Ok(Err(e)) => {
-                        return Err(e);
-                    }
-                    Err(e) => {
-                        return Err(Error::Join(e));
-                    }
-                }
-            } else if pending.is_empty() && running.is_empty() {
-                self.jobs = finished;
-                return Ok(());
-            } else if running.is_empty() {
-                return Err(Error::CircularDependency);
-            }
+    /// Recompute the progress of the job and its tasks from their statuses.
+    fn update_progress(&mut self) {
+        for task in self.tasks.iter_mut().chain(&mut self.hooks) {
+            task.update_progress();
         }
+        let done = self.tasks.iter().filter(|task| task.status.is_done()).count();
+        self.progress = fraction(done, self.tasks.len(), self.status.is_done());
     }
 }
 
 
-#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
-pub enum Status {
-    Pending,
-    Running,
-    Finished,
-    Failed,
+/// Priority and resource limits for a command step. They only take effect
+/// on Unix; elsewhere they're ignored with a warning.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Limits {
+    /// Niceness to run the command at, from -20 (most favoured) to 19 (least)
+    #[serde(default)]
+    pub nice: Option<i32>,
+    /// Most CPU time the command may use, in seconds (`RLIMIT_CPU`)
+    #[serde(default)]
+    pub cpu_limit_secs: Option<u64>,
+    /// Most virtual memory the command may map, in bytes (`RLIMIT_AS`)
+    #[serde(default)]
+    pub memory_limit_bytes: Option<u64>,
 }
 
-impl Default for Status {
-    fn default() -> Status {
-        Status::Pending
+impl Limits {
+    pub fn is_empty(&self) -> bool {
+        self.nice.is_none() && self.cpu_limit_secs.is_none() && self.memory_limit_bytes.is_none()
+    }
+
+    /// Have the command apply the limits to itself between fork and exec.
+    #[cfg(unix)]
+    fn apply(&self, command: &mut tokio::process::Command) {
+        if self.is_empty() {
+            return;
+        }
+        let limits = self.clone();
+        // SAFETY: the closure runs in the forked child, where only
+        // async-signal-safe functions may be called. It only reads the
+        // already-moved `limits` and makes the setpriority and setrlimit
+        // system calls, which neither allocate nor take locks; failures are
+        // reported through errno without allocating either.
+        unsafe {
+            command.pre_exec(move || limits.apply_to_current_process());
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn apply(&self, _command: &mut tokio::process::Command) {
+        if !self.is_empty() {
+            tracing::warn!("Ignoring nice and resource limits, which are only supported on Unix");
+        }
+    }
+
+    #[cfg(unix)]
+    fn apply_to_current_process(&self) -> std::io::Result<()> {
+        if let Some(nice) = self.nice {
+            // SAFETY: a plain system call on this process
+            if unsafe { libc::setpriority(libc::PRIO_PROCESS as _, 0, nice) } != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+        let rlimits = [
+            (libc::RLIMIT_CPU, self.cpu_limit_secs),
+            (libc::RLIMIT_AS, self.memory_limit_bytes),
+        ];
+        for (resource, limit) in rlimits {
+            let Some(limit) = limit else {
+                continue;
+            };
+            let limit = libc::rlimit { rlim_cur: limit as libc::rlim_t, rlim_max: limit as libc::rlim_t };
+            // SAFETY: a plain system call on this process, reading `limit`
+            if unsafe { libc::setrlimit(resource, &limit) } != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+        Ok(())
     }
 }
 
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
-#[serde(untagged)]
-pub enum Step {
-    Command{args: Vec<String>},
+/// A file of tasks named by a job's `include`.
+#[derive(Deserialize)]
+struct Include {
+    #[serde(default)]
+    include: Vec<String>,
+    #[serde(default)]
+    tasks: Vec<Task>,
 }
 
-impl Step {
-    pub fn command(args: Vec<String>) -> Step {
-        Step::Command { args }
+
+pub struct Loader {
+    pub directory: String,
+    /// A single file to load instead of scanning the directory
+    pub file: Option<PathBuf>,
+    /// Glob patterns of further files to load, e.g. `ci/**/*.pipeline.yaml`
+    pub globs: Vec<String>,
+    pub jobs: Vec<Job>,
+    /// Names of the jobs generated from each matrix job
+    pub matrices: HashMap<String, Vec<String>>,
+}
+
+impl Loader {
+    pub fn new(directory: String) -> Loader {
+        Loader {
+            directory,
+            file: None,
+            globs: Vec::new(),
+            jobs: Vec::new(),
+            matrices: HashMap::new(),
+        }
     }
 
-    pub async fn run(&mut self, index: usize, tracker: StepTracker) -> Result<(), Error> {
-        match self {
-            Step::Command { args } => {
-                tracker.modify(index, |step| {
-                    match step {
-                        StepStatus::Command { status, .. } => {
-                            *status = Status::Running;
-                        }
-                    }
-                });
+    /// Also load the files matching a glob pattern. Unlike the directory,
+    /// every matched file is loaded: as JSON if it ends in `.json`, otherwise
+    /// as YAML.
+    pub fn add_glob(&mut self, pattern: impl Into<String>) {
+        self.globs.push(pattern.into());
+    }
 
-                let mut child = tokio::process::Command::new(&args[0])
-                    .args(&args[1..])
-                    .stdin(std::process::Stdio::null())
-                    .stdout(std::process::Stdio::piped())
-                    .stderr(std::process::Stdio::piped())
-                    .spawn()?;
-
-                let stdout = child.stdout.take().unwrap();
-                let tracker_clone = tracker.clone();
-                tokio::spawn(async move {
-                    let mut reader = tokio::io::BufReader::new(stdout);
-                    let mut buffer = String::new();
-                    while reader.read_line(&mut buffer).await.unwrap() > 0 {
-                        tracker_clone.log(index, &buffer);
-                        buffer.clear();
-                    }
-                });
+    /// Load just `path`, whatever its extension, instead of the directory.
+    pub fn from_file(path: impl Into<PathBuf>) -> Loader {
+        let mut loader = Loader::new(String::new());
+        loader.file = Some(path.into());
+        loader
+    }
 
-                let stderr = child.stderr.take().unwrap();
-                let tracker_clone = tracker.clone();
-                tokio::spawn(async move {
-                    let mut reader = tokio::io::BufReader::new(stderr);
-                    let mut buffer = String::new();
-                    while reader.read_line(&mut buffer).await.unwrap() > 0 {
-                        tracker_clone.log(index, &buffer);
-                        buffer.clear();
-                    }
-                });
+    pub fn load(&mut self) -> Result<(), Error> {
+        let mut paths = match &self.file {
+            Some(file) => vec![file.clone()],
+            None => self.directory_files()?,
+        };
+        for pattern in &self.globs {
+            let matches = glob::glob(pattern)
+                .map_err(|e| Error::InvalidPattern(format!("{}: {}", pattern, e)))?;
+            for path in matches {
+                let path = path.map_err(std::io::Error::from)?;
+                if path.is_file() {
+                    paths.push(path);
+                }
+            }
+        }
 
-                let status = child.wait().await?;
-                if status.success() {
-                    tracker.modify(index, |step| {
-                        match step {
-                            StepStatus::Command { status, .. } => {
-                                *status = Status::Finished;
-                            }
-                        }
-                    });
+        // Load files in the same order everywhere, whatever the OS returns,
+        // and only once if several patterns match them
+        paths.sort();
+        paths.dedup();
+        for path in paths {
+            if path.extension().is_some_and(|ext| ext == "json") {
+                self.load_json_file(path)?;
+            } else {
+                self.load_file(path)?;
+            }
+        }
 
-                    Ok(())
-                } else {
-                    tracker.modify(index, |step| {
-                        match step {
-                            StepStatus::Command { status, .. } => {
-                                *status = Status::Failed;
-                            }
-                        }
-                    });
+        Ok(())
+    }
+
+    /// The pipeline files directly inside the directory.
+    fn directory_files(&self) -> Result<Vec<PathBuf>, Error> {
+        let entries = std::fs::read_dir(&self.directory)?;
+        let mut paths = Vec::new();
 
-                    Err(Error::Exit(status))
+        for entry in entries {
+            match entry {
+                Ok(entry) => {
+                    let path = entry.path();
+                    if path.is_file() {
+                        paths.push(path);
+                    }
+                }
+                Err(e) => {
+                    return Err(Error::Io(e));
                 }
             }
         }
+
+        paths.retain(|path| {
+            path.extension().is_some_and(|ext| ext == "yml" || ext == "yaml" || ext == "json")
+        });
+        Ok(paths)
     }
-}
 
+    /// Load jobs from a YAML file, with includes relative to its directory.
+    pub fn load_file(&mut self, path: std::path::PathBuf) -> Result<(), Error> {
+        let yaml = std::fs::read_to_string(&path)?;
+        self.load_jobs(parse_yaml_jobs(&yaml)?, path.parent().unwrap_or(std::path::Path::new("")))
+    }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
-pub enum StepStatus {
-    Command{
-        args: Vec<String>,
-        output: Vec<String>,
-        status: Status
-    },
-}
+    /// Load jobs from a JSON file, with includes relative to its directory.
+    pub fn load_json_file(&mut self, path: std::path::PathBuf) -> Result<(), Error> {
+        let json = std::fs::read_to_string(&path)?;
+        self.load_jobs(parse_json_jobs(&json)?, path.parent().unwrap_or(std::path::Path::new("")))
+    }
 
+    /// Load a job, or an array of jobs, from JSON. Includes are relative to
+    /// the working directory.
+    pub fn load_json_str(&mut self, json: &str) -> Result<(), Error> {
+        self.load_jobs(parse_json_jobs(json)?, std::path::Path::new(""))
+    }
 
-#[derive(Clone)]
-pub struct StepTracker {
-    task_name: String,
-    task_tracker: TaskTracker,
-}
+    /// Load one or more jobs from YAML, separated by `---` when there are
+    /// several. Anchors defined in one document can be aliased in later ones.
+    /// Includes are relative to the working directory.
+    pub fn load_str(&mut self, yaml: &str) -> Result<(), Error> {
+        self.load_jobs(parse_yaml_jobs(yaml)?, std::path::Path::new(""))
+    }
 
-impl StepTracker {
-    pub fn new(task_name: String, task_tracker: TaskTracker) -> StepTracker {
-        StepTracker {
-            task_name,
-            task_tracker,
+    /// Pull in each job's included tasks, resolving paths against `dir`, and
+    /// load the jobs.
+    fn load_jobs(&mut self, jobs: Vec<Job>, dir: &std::path::Path) -> Result<(), Error> {
+        for mut job in jobs {
+            if !job.include.is_empty() {
+                let mut tasks = read_includes(&job.include, dir, &mut Vec::new())?;
+                tasks.append(&mut job.tasks);
+                job.tasks = tasks;
+                job.include.clear();
+            }
+            self.load_job(job)?;
         }
+        Ok(())
     }
 
-    pub fn get(&self, index: usize) -> Option<StepStatus> {
-        match self.task_tracker.get(&self.task_name) {
-            Some(task) => task.steps.get(index).cloned(),
-            None => None,
+    fn load_job(&mut self, job: Job) -> Result<(), Error> {
+        // Skip jobs that don't apply here
+        if let Some(conditions) = &job.conditions {
+            if !conditions.satisfied() {
+                return Ok(());
+            }
+        }
+        let expanded = job.expand()?;
+        if !job.matrix.is_empty() {
+            let names = expanded.iter().map(|job| job.name.clone()).collect();
+            self.matrices.insert(job.name.clone(), names);
+        }
+        // Generated names that collide with other jobs are duplicates
+        for job in expanded {
+            self.add(job)?;
         }
+        Ok(())
     }
 
-    pub fn log(&self, index: usize, message: &str) {
-        print!("{}/{}: {}", self.task_tracker.job_name, self.task_name, message);
-        self.modify(index, |step| {
-            match step {
-                StepStatus::Command { output, .. } => {
-                    output.push(message.to_string());
+    /// Add a job, rejecting names that are already taken, whether by jobs,
+    /// by tasks in the job or by named steps in a task.
+    pub fn add(&mut self, job: Job) -> Result<(), Error> {
+        if self.jobs.iter().any(|other| other.name == job.name) {
+            return Err(Error::DuplicateJob(job.name));
+        }
+        for (index, task) in job.tasks.iter().enumerate() {
+            if job.tasks[..index].iter().any(|other| other.name == task.name) {
+                return Err(Error::DuplicateTask(format!("{}/{}", job.name, task.name)));
+            }
+            for (index, step) in task.steps.iter().enumerate() {
+                let Some(name) = step.name() else {
+                    continue;
+                };
+                if task.steps[..index].iter().any(|other| other.name() == Some(name)) {
+                    return Err(Error::DuplicateStep(format!("{}/{}/{}", job.name, task.name, name)));
                 }
             }
-        });
+        }
+        self.jobs.push(job);
+        Ok(())
     }
 
-    pub fn modify<F>(&self, index: usize, f: F)
-    where
-        F: FnOnce(&mut StepStatus),
-    {
-        self.task_tracker.modify(&self.task_name, |task| {
-            if let Some(step) = task.steps.get_mut(index) {
-                f(step);
-            }
-        });
+    pub fn runner(&self) -> Runner {
+        let mut runner = Runner::new();
+        runner.matrices = self.matrices.clone();
+        // Depending on a matrix job means depending on every combination
+        runner.jobs = self.jobs.iter()
+            .map(|job| {
+                let mut job = job.clone();
+                job.depends = runner.expand_matrices(&job.depends);
+                job
+            })
+            .collect();
+        runner
     }
 }
 
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
-pub struct Task {
-    pub name: String,
-    #[serde(default)]
-    pub depends: Vec<String>,
-    pub steps: Vec<Step>,
+/// How a command's output streams are read.
+#[derive(Clone, Copy, Debug)]
+struct Reading {
+    buffer_size: usize,
+    /// Log each buffer's worth of lines at once
+    chunked: bool,
 }
 
-impl Task {
-    pub fn ready(&self, finished: &Vec<Task>) -> bool {
-        self.depends.iter().all(|name| finished.iter().any(|task| task.name == *name))
-    }
-
-    pub async fn run(&mut self, tracker: StepTracker) -> Result<(), Error> {
-        for (index, step) in &mut self.steps.iter_mut().enumerate() {
-            step.run(index, tracker.clone()).await?
-        }
 
-        Ok(())
-    }
+/// A single line of step output with the context it came from, as written
+/// to the JSON log.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct LogRecord {
+    /// The run the line belongs to, when serving
+    #[serde(default)]
+    pub run_id: Option<u64>,
+    pub job: String,
+    pub task: String,
+    pub step: usize,
+    /// Line number within the step's output, starting at 1
+    pub line: usize,
+    #[serde(with = "humantime_serde")]
+    pub timestamp: SystemTime,
+    pub message: String,
 }
 
 
+/// The state of the whole run, derived from its jobs.
 #[derive(Clone, Debug, Deserialize, Serialize)]
-pub struct TaskStatus {
-    pub name: String,
-    #[serde(default)]
-    pub depends: Vec<String>,
-    pub steps: Vec<StepStatus>,
-    #[serde(default)]
+pub struct PipelineStatus {
+    pub jobs: Vec<JobStatus>,
     pub status: Status,
+    #[serde(default, with = "humantime_serde")]
+    pub started_at: Option<SystemTime>,
+    #[serde(default, with = "humantime_serde")]
+    pub finished_at: Option<SystemTime>,
 }
 
+impl PipelineStatus {
+    pub fn new(jobs: Vec<JobStatus>) -> PipelineStatus {
+        let any = |status: Status| jobs.iter().any(|job| job.status == status);
+        let status = if any(Status::Failed) {
+            Status::Failed
+        } else if any(Status::Running) {
+            Status::Running
+        } else if any(Status::Pending) {
+            Status::Pending
+        } else if any(Status::Cancelled) {
+            Status::Cancelled
+        } else {
+            Status::Finished
+        };
 
-#[derive(Clone)]
-pub struct TaskTracker {
-    job_name: String,
-    job_tracker: JobTracker,
+        let started_at = jobs.iter().filter_map(|job| job.started_at).min();
+        let finished_at = match status {
+            Status::Finished | Status::Failed | Status::Cancelled if !any(Status::Running) => {
+                jobs.iter().filter_map(|job| job.finished_at).max()
+            }
+            _ => None,
+        };
+
+        PipelineStatus { jobs, status, started_at, finished_at }
+    }
 }
 
-impl TaskTracker {
-    pub fn new(job_name: String, job_tracker: JobTracker) -> TaskTracker {
-        TaskTracker {
-            job_name,
-            job_tracker,
+
+#[derive(Clone, Default)]
+pub struct Runner {
+    pub jobs: Vec<Job>,
+    /// Directory to copy each job's artifacts to, as `<job>/<path>`
+    pub artifact_dir: Option<PathBuf>,
+    /// Called with every status change the runner makes
+    pub callbacks: Vec<Arc<StatusCallback>>,
+    /// Keep running independent jobs after a failure instead of stopping
+    pub continue_on_error: bool,
+    /// Program and arguments to prefix every command with
+    pub command_wrapper: Vec<String>,
+    /// Directory to write per-step logs to, as `<job>/<task>/<step>.log`
+    pub log_dir: Option<PathBuf>,
+    /// File to append a JSON `LogRecord` to for every line of output
+    pub log_json: Option<PathBuf>,
+    /// Print command output to our own stdout and stderr, starting each line
+    /// with this template; `{job}`, `{task}`, `{step}` and `{timestamp}` are
+    /// filled in
+    pub log_prefix: Option<String>,
+    /// Most lines of output to keep per step, dropping the oldest first
+    pub max_output_lines: Option<usize>,
+    /// Write command output straight to our own stdout and stderr instead of
+    /// logging it
+    pub mirror_output: bool,
+    /// Prefix of output lines that set a step's progress percentage
+    pub progress_prefix: String,
+    /// Values to replace with `***` wherever command output is printed,
+    /// stored or written
+    pub secrets: Vec<String>,
+    /// Jobs left out of the run, reported as skipped
+    pub skipped: Vec<Job>,
+    /// Deadline for the whole run, after which running jobs are stopped and
+    /// fail, though their hooks still run
+    pub timeout_secs: Option<u64>,
+    /// Names of the jobs generated from each matrix job, so that naming the
+    /// matrix job to `filter` or `skip` means all of them
+    pub matrices: HashMap<String, Vec<String>>,
+}
+
+impl Runner {
+    pub fn new() -> Runner {
+        Runner {
+            jobs: Vec::new(),
+            artifact_dir: None,
+            callbacks: Vec::new(),
+            continue_on_error: false,
+            command_wrapper: Vec::new(),
+            log_dir: None,
+            log_json: None,
+            log_prefix: None,
+            max_output_lines: None,
+            mirror_output: false,
+            progress_prefix: DEFAULT_PROGRESS_PREFIX.to_string(),
+            secrets: Vec::new(),
+            skipped: Vec::new(),
+            timeout_secs: None,
+            matrices: HashMap::new(),
         }
     }
 
-    pub fn get(&self, name: &str) -> Option<TaskStatus> {
-        match self.job_tracker.get(&self.job_name) {
-            Some(job) => job.tasks.iter().find(|task| task.name == name).cloned(),
-            None => None,
-        }
+    /// `names`, with each matrix job's name replaced by its generated jobs.
+    pub fn expand_matrices(&self, names: &[String]) -> Vec<String> {
+        names.iter()
+            .flat_map(|name| match self.matrices.get(name) {
+                Some(names) => names.clone(),
+                None => vec![name.clone()],
+            })
+            .collect()
     }
 
-    pub fn modify<F>(&self, name: &str, f: F)
-    where
-        F: FnOnce(&mut TaskStatus),
+    /// Keep only the named jobs and everything they transitively depend on.
+    pub fn filter(&mut self, names: &[String]) -> Result<(), Error> {
+        let mut keep: Vec<String> = Vec::new();
+        let mut queue = self.expand_matrices(names);
+
+        while let Some(name) = queue.pop() {
+            if keep.contains(&name) {
+                continue;
+            }
+            match self.jobs.iter().find(|job| job.name == name) {
+                Some(job) => queue.extend(job.depends.iter().cloned()),
+                None => return Err(Error::MissingDependency(name)),
+            }
+            keep.push(name);
+        }
+
+        self.jobs.retain(|job| keep.contains(&job.name));
+        Ok(())
+    }
+
+    /// Keep only the jobs tagged with `tag` and everything they transitively
+    /// depend on.
+    pub fn filter_by_tag(&mut self, tag: &str) -> Result<(), Error> {
+        let names: Vec<String> = self.jobs.iter()
+            .filter(|job| job.tags.iter().any(|t| t == tag))
+            .map(|job| job.name.clone())
+            .collect();
+        if names.is_empty() {
+            return Err(Error::UnknownTag(tag.to_string()));
+        }
+        self.filter(&names)
+    }
+
+    /// Leave the named jobs out of the run, along with everything that
+    /// transitively depends on them, and report them all as skipped.
+    pub fn skip(&mut self, names: &[String]) {
+        let mut blocked = self.expand_matrices(names);
+
+        while let Some(name) = blocked.pop() {
+            let mut i = 0;
+            while i < self.jobs.len() {
+                let job = &self.jobs[i];
+                if job.name == name || job.depends.contains(&name) {
+                    let job = self.jobs.remove(i);
+                    if job.name != name {
+                        blocked.push(job.name.clone());
+                    }
+                    self.skipped.push(job);
+                } else {
+                    i += 1;
+                }
+            }
+        }
+    }
+
+    /// Job names in an order they could run one at a time, breaking ties by
+    /// name so the order is stable.
+    pub fn topo_order(&self) -> Result<Vec<String>, Error> {
+        let names: HashSet<&str> = self.jobs.iter().map(|job| job.name.as_str()).collect();
+        let mut remaining: HashMap<&str, usize> = HashMap::new();
+        let mut dependents: HashMap<&str, Vec<&str>> = HashMap::new();
+        for job in &self.jobs {
+            for name in &job.depends {
+                if !names.contains(name.as_str()) {
+                    return Err(Error::MissingDependency(name.clone()));
+                }
+                dependents.entry(name.as_str()).or_default().push(&job.name);
+            }
+            remaining.insert(&job.name, job.depends.len());
+        }
+
+        let mut ready: BTreeSet<&str> = remaining.iter()
+            .filter(|(_, count)| **count == 0)
+            .map(|(name, _)| *name)
+            .collect();
+        let mut order = Vec::new();
+
+        while let Some(name) = ready.pop_first() {
+            order.push(name.to_string());
+            for dependent in dependents.get(name).into_iter().flatten() {
+                let left = remaining.get_mut(dependent).unwrap();
+                *left -= 1;
+                if *left == 0 {
+                    ready.insert(*dependent);
+                }
+            }
+        }
+
+        if order.len() < self.jobs.len() {
+            let graph: Vec<(String, Vec<String>)> = self.jobs.iter()
+                .map(|job| (job.name.clone(), job.depends.clone()))
+                .collect();
+            return Err(Error::CircularDependency(unorderable(&graph)));
+        }
+        Ok(order)
+    }
+
+    /// Check that every job, task and step dependency refers to something
+    /// that exists and that none of them go round in a circle, reporting all
+    /// the problems at once.
+    pub fn validate(&self) -> Result<(), Error> {
+        let mut errors = Vec::new();
+
+        let graph: Vec<(String, Vec<String>)> = self.jobs.iter()
+            .map(|job| (job.name.clone(), job.depends.clone()))
+            .collect();
+        let cycle = unorderable(&graph);
+        if !cycle.is_empty() {
+            errors.push(Error::CircularDependency(cycle));
+        }
+
+        let names: HashSet<&str> = self.jobs.iter().map(|job| job.name.as_str()).collect();
+        for job in &self.jobs {
+            for name in &job.depends {
+                if !names.contains(name.as_str()) {
+                    errors.push(Error::MissingDependency(name.clone()));
+                }
+            }
+
+            let cycle = job.task_cycle();
+            if !cycle.is_empty() {
+                errors.push(Error::CircularDependency(cycle));
+            }
+
+            for task in &job.tasks {
+                if let (Some(for_each), None) = (&task.for_each, task.producer()) {
+                    let reference = format!("{}/{}: {}", job.name, task.name, for_each);
+                    errors.push(Error::InvalidReference(reference));
+                }
+                for name in task.depends.iter().map(String::as_str).chain(task.producer()) {
+                    if !job.tasks.iter().any(|task| task.name == name) {
+                        let name = format!("{}/{}", job.name, name);
+                        errors.push(Error::MissingDependency(name));
+                    }
+                }
+                for name in task.steps.iter().flat_map(Step::depends) {
+                    if !task.steps.iter().any(|step| step.name() == Some(name)) {
+                        let name = format!("{}/{}/{}", job.name, task.name, name);
+                        errors.push(Error::MissingDependency(name));
+                    }
+                }
+                let cycle = task.step_cycle(&job.name);
+                if !cycle.is_empty() {
+                    errors.push(Error::CircularDependency(cycle));
+                }
+            }
+        }
+
+        collect_errors(errors)
+    }
+
+    /// Call `callback` with every job, task and step status change the
+    /// runner makes, synchronously and without the tracker locked.
+    pub fn on_status_change<F>(&mut self, callback: F)
+    where
+        F: Fn(&StatusEvent) + Send + Sync + 'static,
+    {
+        self.callbacks.push(Arc::new(callback));
+    }
+
+    /// Run every job in dependency order. Which jobs and tasks become ready,
+    /// and what the rest are waiting on, is logged at debug level under the
+    /// `bed::scheduler` target.
+    pub async fn run(&mut self, tracker: JobTracker) -> Result<(), Error> {
+        self.run_until(tracker, std::future::pending()).await
+    }
+
+    /// Run every job like `run`, until `stop` completes first. The jobs still
+    /// running are then cancelled, running their hooks, and the jobs waiting
+    /// to start never do.
+    pub async fn run_until<F>(&mut self, tracker: JobTracker, stop: F) -> Result<(), Error>
+    where
+        F: Future<Output = ()>,
+    {
+        self.validate()?;
+        let tracker = self.with_callbacks(&tracker);
+
+        for job in &self.jobs {
+            // Create a job status
+            tracker.insert(JobStatus::new(job));
+        }
+        for job in &self.skipped {
+            let mut status = JobStatus::new(job);
+            status.set_status(Status::Skipped, tracker.now());
+            let skipped = job.depends.iter()
+                .find(|name| self.skipped.iter().any(|job| job.name == **name));
+            status.reason = Some(match skipped {
+                Some(name) => format!("Depends on skipped job {}", name),
+                None => "Skipped on request".to_string(),
+            });
+            tracker.insert(status);
+        }
+
+        let log_json = self.open_log_json()?;
+        let deadline = self.timeout_secs.map(|secs| Instant::now() + Duration::from_secs(secs));
+
+        // Move the jobs out to run them rather than cloning each one, and
+        // put them all back once the run is over
+        let names: Vec<String> = self.jobs.iter().map(|job| job.name.clone()).collect();
+        let mut pending: Vec<Option<Job>> = std::mem::take(&mut self.jobs).into_iter().map(Some).collect();
+        let mut running = Vec::new();
+        let mut finished = Vec::new();
+        let mut stopped = Vec::new();
+        let mut errors = Vec::new();
+        let mut spawned = AbortOnDrop::default();
+        let mut stop = std::pin::pin!(stop);
+
+        let result = loop {
+            // Spawn the jobs that are ready to run
+            for slot in &mut pending {
+                if let Some(job) = slot.take_if(|job| job.ready(&finished)) {
+                    tracing::debug!(target: "bed::scheduler", job = %job.name, "Job ready");
+                    let handle = self.spawn(job, &tracker, log_json.clone(), deadline);
+                    spawned.0.push(handle.abort_handle());
+                    running.push(handle);
+                }
+            }
+            pending.retain(Option::is_some);
+            for job in pending.iter().flatten() {
+                let waiting_on = job.waiting_on(&finished);
+                tracing::debug!(target: "bed::scheduler", job = %job.name, ?waiting_on, "Job waiting");
+            }
+
+            if !running.is_empty() {
+                // Wait for any job to finish
+                let done = tokio::select! {
+                    done = select_running(&mut running, deadline) => done,
+                    _ = &mut stop => break Err(Error::Cancelled("pipeline".to_string())),
+                };
+                let Some(done) = done else {
+                    break Err(Error::Timeout("pipeline".to_string()));
+                };
+                // Match the result of the job
+                match done {
+                    Ok((job, Ok(()))) => {
+                        // Add the job to the finished list
+                        finished.push(job);
+                    }
+                    Ok((job, Err(e))) => {
+                        // Skip everything downstream of the failed job
+                        let cancelled = matches!(e, Error::Cancelled(_));
+                        let failed = job.name.clone();
+                        stopped.push(job);
+                        let mut blocked = vec![failed.clone()];
+                        while let Some(name) = blocked.pop() {
+                            let reason = if name != failed {
+                                format!("Depends on skipped job {}", name)
+                            } else if cancelled {
+                                format!("Dependency {} was cancelled", name)
+                            } else {
+                                format!("Dependency {} failed", name)
+                            };
+                            for slot in &mut pending {
+                                if let Some(job) = slot.take_if(|job| job.depends.contains(&name)) {
+                                    tracker.modify(&job.name, |job| {
+                                        job.set_status(Status::Skipped, tracker.now());
+                                        job.reason = Some(reason.clone());
+                                    });
+                                    blocked.push(job.name.clone());
+                                    stopped.push(job);
+                                }
+                            }
+                            pending.retain(Option::is_some);
+                        }
+                        // A cancelled job only stops what depends on it
+                        if !self.continue_on_error && !cancelled {
+                            break Err(e);
+                        }
+                        errors.push(e);
+                    }
+                    Err(e) => {
+                        break Err(Error::Join(e));
+                    }
+                }
+            } else if pending.is_empty() && running.is_empty() {
+                break collect_errors(errors);
+            } else if running.is_empty() {
+                let names = pending.iter().flatten().map(|job| job.name.clone()).collect();
+                errors.push(Error::Unsatisfiable(names));
+                break collect_errors(errors);
+            }
+        };
+
+        // Cancel whatever is still running when the run stops early, and wait
+        // for it to run its hooks and hand its job back. Out of time, the jobs
+        // stop themselves at the same deadline, failing instead.
+        if !running.is_empty() {
+            for name in &names {
+                tracker.cancel(name);
+            }
+            for (job, _) in futures::future::join_all(running).await.into_iter().flatten() {
+                stopped.push(job);
+            }
+        }
+
+        // Put the jobs back in the order they were given
+        let mut jobs: Vec<Job> = finished.into_iter()
+            .chain(stopped)
+            .chain(pending.into_iter().flatten())
+            .collect();
+        jobs.sort_by_key(|job| names.iter().position(|name| *name == job.name));
+        self.jobs = jobs;
+        result
+    }
+
+    /// Re-run a single job from scratch, provided it isn't pending or running
+    /// and all of its dependencies have finished.
+    pub fn retry(
+        &self,
+        name: &str,
+        tracker: &JobTracker,
+    ) -> Result<JobHandle, Error> {
+        let job = match self.jobs.iter().find(|job| job.name == name) {
+            Some(job) => job,
+            None => return Err(Error::MissingDependency(name.to_string())),
+        };
+
+        match tracker.get(name) {
+            Some(status) if status.status != Status::Pending && status.status != Status::Running => {}
+            _ => return Err(Error::JobNotReady(name.to_string())),
+        }
+
+        for dependency in &job.depends {
+            match tracker.get(dependency) {
+                Some(status) if status.status == Status::Finished => {}
+                _ => return Err(Error::JobNotReady(name.to_string())),
+            }
+        }
+
+        let tracker = self.with_callbacks(tracker);
+        tracker.modify(name, JobStatus::reset);
+        Ok(self.spawn(job.clone(), &tracker, self.open_log_json()?, None))
+    }
+
+    fn with_callbacks(&self, tracker: &JobTracker) -> JobTracker {
+        self.callbacks.iter().fold(tracker.clone(), |tracker, callback| {
+            tracker.on_status_change(callback.clone())
+        })
+    }
+
+    fn open_log_json(&self) -> Result<Option<Arc<Mutex<std::fs::File>>>, Error> {
+        match &self.log_json {
+            Some(path) => {
+                let file = std::fs::OpenOptions::new().create(true).append(true).open(path)?;
+                Ok(Some(Arc::new(Mutex::new(file))))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// A tracker for a job's tasks that logs and captures their output the
+    /// way this runner's jobs do, for running steps outside of `run`.
+    pub fn task_tracker(&self, job_name: impl Into<String>, tracker: &JobTracker) -> Result<TaskTracker, Error> {
+        let task_tracker = TaskTracker::new(job_name.into(), tracker.clone());
+        Ok(self.configure(task_tracker, self.open_log_json()?))
+    }
+
+    fn configure(&self, mut task_tracker: TaskTracker, log_json: Option<Arc<Mutex<std::fs::File>>>) -> TaskTracker {
+        task_tracker.artifact_dir = self.artifact_dir.clone();
+        task_tracker.command_wrapper(self.command_wrapper.clone());
+        task_tracker.log_dir = self.log_dir.clone();
+        task_tracker.log_json = log_json;
+        task_tracker.log_prefix = self.log_prefix.clone();
+        task_tracker.max_output_lines = self.max_output_lines;
+        task_tracker.mirror_output = self.mirror_output;
+        task_tracker.progress_prefix = self.progress_prefix.clone();
+        task_tracker.secrets = self.secrets.iter().filter(|secret| !secret.is_empty()).cloned().collect();
+        task_tracker
+    }
+
+    /// Mark a job running and run it in the background, recording its final
+    /// status when it completes. It's stopped if it's cancelled or still
+    /// running at `deadline`.
+    fn spawn(
+        &self,
+        mut job: Job,
+        tracker: &JobTracker,
+        log_json: Option<Arc<Mutex<std::fs::File>>>,
+        deadline: Option<Instant>,
+    ) -> JobHandle {
+        let task_tracker = self.configure(TaskTracker::new(job.name.clone(), tracker.clone()), log_json);
+
+        tracker.modify(&job.name, |job| {
+            job.set_status(Status::Running, tracker.now());
+        });
+
+        let cancel = Arc::new(Notify::new());
+        tracker.cancels.lock().insert(job.name.clone(), cancel.clone());
+
+        let tracker = tracker.clone();
+        tokio::spawn(async move {
+            let name = job.name.clone();
+            let stop = async {
+                tokio::select! {
+                    // Running out of time wins over being cancelled with the rest
+                    biased;
+                    _ = until(deadline) => Error::Timeout("pipeline".to_string()),
+                    _ = cancel.notified() => Error::Cancelled(name.clone()),
+                }
+            };
+            let result = job.run(task_tracker, stop).await;
+            tracker.cancels.lock().remove(&name);
+
+            let status = match result {
+                Ok(()) => Status::Finished,
+                Err(Error::Cancelled(_)) => Status::Cancelled,
+                Err(_) => Status::Failed,
+            };
+            tracker.modify(&name, |job| job.set_status(status, tracker.now()));
+            (job, result)
+        })
+    }
+}
+
+
+/// The current run, tracked live, and snapshots of the runs before it.
+#[derive(Clone)]
+pub struct RunStore {
+    tracker: JobTracker,
+    limit: usize,
+    runs: Arc<Mutex<RunHistory>>,
+}
+
+#[derive(Default)]
+struct RunHistory {
+    current: u64,
+    past: VecDeque<(u64, PipelineStatus)>,
+}
+
+impl RunStore {
+    /// Track runs with `tracker`, keeping at most `limit` past runs.
+    pub fn new(tracker: JobTracker, limit: usize) -> RunStore {
+        RunStore { tracker, limit, runs: Arc::new(Mutex::new(RunHistory::default())) }
+    }
+
+    /// Id of the current run, or 0 before the first one starts.
+    pub fn current(&self) -> u64 {
+        self.runs.lock().current
+    }
+
+    pub fn get(&self, id: u64) -> Option<PipelineStatus> {
+        let runs = self.runs.lock();
+        if id != 0 && id == runs.current {
+            return Some(PipelineStatus::new(self.tracker.list()));
+        }
+        runs.past.iter().find(|(past, _)| *past == id).map(|(_, status)| status.clone())
+    }
+
+    /// Summaries of the kept runs, oldest first.
+    pub fn list(&self) -> Vec<RunSummary> {
+        let runs = self.runs.lock();
+        let mut list: Vec<RunSummary> = runs.past.iter()
+            .map(|(id, status)| RunSummary::new(*id, status))
+            .collect();
+        if runs.current != 0 {
+            let status = PipelineStatus::new(self.tracker.list());
+            list.push(RunSummary::new(runs.current, &status));
+        }
+        list
+    }
+
+    /// Archive the current run and clear the tracker for a new one,
+    /// returning the new run's id.
+    pub fn start(&self) -> u64 {
+        let mut runs = self.runs.lock();
+        if runs.current != 0 {
+            let id = runs.current;
+            runs.past.push_back((id, PipelineStatus::new(self.tracker.list())));
+            while runs.past.len() > self.limit {
+                runs.past.pop_front();
+            }
+        }
+        self.tracker.clear();
+        runs.current += 1;
+        self.tracker.run_id.store(runs.current, Ordering::Relaxed);
+        runs.current
+    }
+}
+
+/// A run's outcome, without the details of its jobs.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RunSummary {
+    pub id: u64,
+    pub status: Status,
+    #[serde(default, with = "humantime_serde")]
+    pub started_at: Option<SystemTime>,
+    #[serde(default, with = "humantime_serde")]
+    pub finished_at: Option<SystemTime>,
+}
+
+impl RunSummary {
+    pub fn new(id: u64, pipeline: &PipelineStatus) -> RunSummary {
+        RunSummary {
+            id,
+            status: pipeline.status.clone(),
+            started_at: pipeline.started_at,
+            finished_at: pipeline.finished_at,
+        }
+    }
+}
+
+
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub enum Status {
+    #[default]
+    Pending,
+    Running,
+    Finished,
+    Failed,
+    Skipped,
+    /// Stopped on request before it completed
+    Cancelled,
+}
+
+impl Status {
+    /// Whether this is a final status, counting towards progress.
+    pub fn is_done(&self) -> bool {
+        matches!(self, Status::Finished | Status::Failed | Status::Skipped | Status::Cancelled)
+    }
+
+    /// Record the time of a transition into this status, and the elapsed
+    /// time once it completes.
+    fn stamp(
+        &self,
+        now: SystemTime,
+        started_at: &mut Option<SystemTime>,
+        finished_at: &mut Option<SystemTime>,
+        duration_ms: &mut Option<u64>,
+    ) {
+        match self {
+            Status::Running => {
+                *started_at = Some(now);
+                *finished_at = None;
+                *duration_ms = None;
+            }
+            Status::Finished | Status::Failed | Status::Cancelled => {
+                *finished_at = Some(now);
+                *duration_ms = started_at
+                    .and_then(|started_at| now.duration_since(started_at).ok())
+                    .map(|duration| duration.as_millis() as u64);
+            }
+            Status::Pending | Status::Skipped => {}
+        }
+    }
+}
+
+
+/// A change in the status of a job, or of one of its tasks or steps.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct StatusEvent {
+    pub job: String,
+    #[serde(default)]
+    pub task: Option<String>,
+    #[serde(default)]
+    pub step: Option<usize>,
+    pub status: Status,
+}
+
+
+/// How many jobs ended up in each status, and which ones failed.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct StatusSummary {
+    pub total: usize,
+    pub pending: usize,
+    pub running: usize,
+    pub finished: usize,
+    pub failed: usize,
+    pub skipped: usize,
+    pub cancelled: usize,
+    /// Names of the failed jobs, in name order
+    pub failed_jobs: Vec<String>,
+}
+
+impl StatusSummary {
+    pub fn new(jobs: &[JobStatus]) -> StatusSummary {
+        let mut summary = StatusSummary { total: jobs.len(), ..StatusSummary::default() };
+        for job in jobs {
+            match job.status {
+                Status::Pending => summary.pending += 1,
+                Status::Running => summary.running += 1,
+                Status::Finished => summary.finished += 1,
+                Status::Failed => {
+                    summary.failed += 1;
+                    summary.failed_jobs.push(job.name.clone());
+                }
+                Status::Skipped => summary.skipped += 1,
+                Status::Cancelled => summary.cancelled += 1,
+            }
+        }
+        summary.failed_jobs.sort();
+        summary
+    }
+}
+
+/// E.g. `5 jobs: 4 finished, 1 failed (deploy)`, leaving out empty counts.
+impl std::fmt::Display for StatusSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} job{}", self.total, if self.total == 1 { "" } else { "s" })?;
+        let counts = [
+            (self.finished, "finished"),
+            (self.failed, "failed"),
+            (self.cancelled, "cancelled"),
+            (self.skipped, "skipped"),
+            (self.running, "running"),
+            (self.pending, "pending"),
+        ];
+        let mut separator = ": ";
+        for (count, label) in counts {
+            if count == 0 {
+                continue;
+            }
+            write!(f, "{}{} {}", separator, count, label)?;
+            if label == "failed" {
+                write!(f, " ({})", self.failed_jobs.join(", "))?;
+            }
+            separator = ", ";
+        }
+        Ok(())
+    }
+}
+
+
+/// A pull or merge request to comment on.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum PullRequest {
+    GitHub {
+        api_url: String,
+        /// `<owner>/<repo>`
+        repository: String,
+        number: u64,
+    },
+    GitLab {
+        api_url: String,
+        /// The project's numeric id
+        project: String,
+        number: u64,
+    },
+}
+
+/// Posts a run's summary as a comment on the pull or merge request it's
+/// building.
+#[derive(Clone, Debug)]
+pub struct Reporter {
+    pub pull_request: PullRequest,
+    pub token: String,
+}
+
+impl Reporter {
+    /// A reporter for the pull request in GitHub Actions' or GitLab CI's
+    /// environment, if it names one and there's a token to post with.
+    pub fn from_env() -> Option<Reporter> {
+        Reporter::from_vars(|name| std::env::var(name).ok())
+    }
+
+    /// Like `from_env`, looking variables up with `var`. The token comes
+    /// from `BED_REPORT_TOKEN`, or else `GITHUB_TOKEN` or `GITLAB_TOKEN`. On
+    /// GitHub the pull request number is taken from `BED_PULL_REQUEST`, or
+    /// else a `refs/pull/<number>/merge` `GITHUB_REF`.
+    pub fn from_vars<F>(var: F) -> Option<Reporter>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let token = |fallback: &str| var("BED_REPORT_TOKEN").or_else(|| var(fallback));
+
+        if let Some(repository) = var("GITHUB_REPOSITORY") {
+            let from_ref = || var("GITHUB_REF")?
+                .strip_prefix("refs/pull/")?
+                .strip_suffix("/merge")
+                .map(String::from);
+            let number = var("BED_PULL_REQUEST").or_else(from_ref)?.parse().ok()?;
+            let api_url = var("GITHUB_API_URL").unwrap_or_else(|| "https://api.github.com".to_string());
+            let pull_request = PullRequest::GitHub { api_url, repository, number };
+            return Some(Reporter { pull_request, token: token("GITHUB_TOKEN")? });
+        }
+
+        if let Some(project) = var("CI_PROJECT_ID") {
+            let number = var("CI_MERGE_REQUEST_IID")?.parse().ok()?;
+            let api_url = var("CI_API_V4_URL").unwrap_or_else(|| "https://gitlab.com/api/v4".to_string());
+            let pull_request = PullRequest::GitLab { api_url, project, number };
+            return Some(Reporter { pull_request, token: token("GITLAB_TOKEN")? });
+        }
+
+        None
+    }
+
+    /// Post `tracker`'s statuses as a comment, formatted by `to_markdown`.
+    pub async fn post(&self, tracker: &JobTracker) -> Result<(), Error> {
+        let body = serde_json::to_string(&serde_json::json!({ "body": tracker.to_markdown() }))?;
+        let request = match &self.pull_request {
+            PullRequest::GitHub { api_url, repository, number } => {
+                let url = format!("{}/repos/{}/issues/{}/comments", api_url, repository, number);
+                reqwest::Client::new().post(url)
+                    .bearer_auth(&self.token)
+                    .header("Accept", "application/vnd.github+json")
+            }
+            PullRequest::GitLab { api_url, project, number } => {
+                let url = format!("{}/projects/{}/merge_requests/{}/notes", api_url, project, number);
+                reqwest::Client::new().post(url).header("PRIVATE-TOKEN", &self.token)
+            }
+        };
+
+        let response = request
+            .header("Content-Type", "application/json")
+            .header("User-Agent", "bed")
+            .body(body)
+            .send()
+            .await?;
+        if response.status().is_success() {
+            Ok(())
+        } else {
+            Err(Error::HttpStatus(response.url().to_string(), response.status().as_u16()))
+        }
+    }
+}
+
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum Step {
+    Command{
+        /// Identifies the step in logs, statuses and `${steps.<name>.output}`
+        #[serde(default)]
+        name: Option<String>,
+        /// Names of the steps in the task to wait for. Without it, the step
+        /// waits for the one before it; with an empty list, it starts
+        /// straight away alongside the others.
+        #[serde(default)]
+        depends: Option<Vec<String>>,
+        /// The program and its arguments, or a single string split like a shell would
+        #[serde(deserialize_with = "deserialize_args")]
+        args: Vec<String>,
+        /// Capacity of the output readers, for steps that emit a lot of
+        /// output; anything below 1 byte is taken as 1
+        #[serde(default)]
+        buffer_size: Option<usize>,
+        /// Read output a buffer at a time and record all the complete lines
+        /// in it together, rather than one line at a time. For a million
+        /// short lines this ran about 13x faster with a log directory, 2x
+        /// mirroring output or writing JSON logs, and 1.1x logging each line
+        /// at info level, where formatting the log dominates.
+        #[serde(default)]
+        chunked: bool,
+        /// Let the task carry on if this step fails; the step is still marked failed
+        #[serde(default)]
+        continue_on_error: bool,
+        /// Environment variables for the command, overriding the task's and job's
+        #[serde(default)]
+        env: HashMap<String, String>,
+        /// Content to write to the command's stdin, which is otherwise empty
+        #[serde(default)]
+        stdin: Option<String>,
+        /// Skip the step unless this path exists
+        #[serde(default)]
+        only_if_exists: Option<String>,
+        /// Skip the step if this path exists, e.g. an artifact from an earlier run
+        #[serde(default)]
+        unless_exists: Option<String>,
+        /// Priority and resource limits, given alongside the other fields
+        #[serde(flatten)]
+        limits: Limits,
+    },
+    File{
+        path: String,
+        content: String,
+        /// Permissions to set on the file, applied on Unix only
+        #[serde(default)]
+        mode: Option<u32>,
+    },
+    Http{
+        method: String,
+        url: String,
+        #[serde(default)]
+        body: Option<String>,
+    },
+    /// Run a script file with `interpreter`, or else the interpreter named by
+    /// its `#!` line, or else `sh`
+    Script{
+        path: String,
+        #[serde(default)]
+        interpreter: Option<Vec<String>>,
+    },
+}
+
+impl Step {
+    pub fn command(args: Vec<String>) -> Step {
+        Step::Command {
+            name: None,
+            depends: None,
+            args,
+            buffer_size: None,
+            chunked: false,
+            continue_on_error: false,
+            env: HashMap::new(),
+            stdin: None,
+            only_if_exists: None,
+            unless_exists: None,
+            limits: Limits::default(),
+        }
+    }
+
+    /// Names of the steps this one explicitly waits for.
+    pub fn depends(&self) -> &[String] {
+        match self {
+            Step::Command { depends: Some(depends), .. } => depends,
+            _ => &[],
+        }
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        match self {
+            Step::Command { name, .. } => name.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// Whether the step at `index` in `steps` can start, given the indices of
+    /// the steps that have finished.
+    pub fn ready(&self, index: usize, steps: &[Step], finished: &[usize]) -> bool {
+        match self {
+            Step::Command { depends: Some(depends), .. } => depends.iter().all(|name| {
+                finished.iter().any(|&finished| steps[finished].name() == Some(name))
+            }),
+            _ => index == 0 || finished.contains(&(index - 1)),
+        }
+    }
+
+    pub async fn run(&mut self, index: usize, tracker: StepTracker) -> Result<(), Error> {
+        if let Step::Command { only_if_exists, unless_exists, .. } = self {
+            let exists = |path: &String| tracker.task_tracker.resolve(path).exists();
+            let only_if = only_if_exists.as_ref().is_none_or(exists);
+            let unless = unless_exists.as_ref().is_some_and(exists);
+            if !only_if || unless {
+                tracker.modify(index, |step| {
+                    step.set_status(Status::Skipped, tracker.now());
+                });
+                tracing::info!(
+                    job = %tracker.task_tracker.job_name,
+                    task = %tracker.task_name,
+                    step = %tracker.label(index),
+                    "Step skipped",
+                );
+                return Ok(());
+            }
+        }
+
+        tracker.modify(index, |step| {
+            step.set_status(Status::Running, tracker.now());
+        });
+        tracing::info!(
+            job = %tracker.task_tracker.job_name,
+            task = %tracker.task_name,
+            step = %tracker.label(index),
+            "Step started",
+        );
+
+        let result = match self {
+            Step::Command { args, .. } if args.is_empty() => {
+                Err(Error::EmptyCommand(Box::new(self.clone())))
+            }
+            Step::Command { args, buffer_size, chunked, env, stdin, limits, .. } => {
+                // An empty buffer would read as the end of the output straight away
+                let reading = Reading {
+                    buffer_size: buffer_size.unwrap_or(DEFAULT_BUFFER_SIZE).max(1),
+                    chunked: *chunked,
+                };
+                Step::run_command(args, reading, env, stdin.clone(), limits, index, &tracker).await
+            }
+            Step::File { path, content, mode } => {
+                Step::write_file(&tracker.task_tracker.resolve(path), content, *mode).await
+            }
+            Step::Http { method, url, body } => {
+                Step::request(method, url, body.clone(), index, &tracker).await
+            }
+            Step::Script { path, interpreter } => {
+                match Step::script_args(&tracker.task_tracker.resolve(path), interpreter).await {
+                    Ok(args) => {
+                        let env = HashMap::new();
+                        let reading = Reading { buffer_size: DEFAULT_BUFFER_SIZE, chunked: false };
+                        Step::run_command(&args, reading, &env, None, &Limits::default(), index, &tracker).await
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+        };
+
+        // Keep the end of a failed command's output with its error
+        let result = match result {
+            Err(Error::Exit(status)) => {
+                let output = match tracker.get(index) {
+                    Some(StepStatus::Command { output, .. } | StepStatus::Script { output, .. }) => {
+                        output[output.len().saturating_sub(FAILED_OUTPUT_LINES)..].to_vec()
+                    }
+                    _ => Vec::new(),
+                };
+                let step = format!(
+                    "{}/{}/{}",
+                    tracker.task_tracker.job_name,
+                    tracker.task_name,
+                    tracker.label(index),
+                );
+                Err(Error::StepFailed { step, output, status })
+            }
+            result => result,
+        };
+
+        match &result {
+            Ok(()) => {
+                tracker.modify(index, |step| {
+                    step.set_status(Status::Finished, tracker.now());
+                });
+                tracing::info!(
+                    job = %tracker.task_tracker.job_name,
+                    task = %tracker.task_name,
+                    step = %tracker.label(index),
+                    "Step finished",
+                );
+            }
+            Err(e) => {
+                tracker.modify(index, |step| {
+                    step.set_status(Status::Failed, tracker.now());
+                });
+                tracing::warn!(
+                    job = %tracker.task_tracker.job_name,
+                    task = %tracker.task_name,
+                    step = %tracker.label(index),
+                    error = %e,
+                    "Step failed",
+                );
+
+                if let Step::Command { continue_on_error: true, .. } = self {
+                    return Ok(());
+                }
+            }
+        }
+
+        result
+    }
+
+    /// Rewrite every templated string in the step: command arguments, stdin
+    /// and existence checks, file path and content, and HTTP URL and body.
+    pub fn substitute<F>(&mut self, mut f: F) -> Result<(), Error>
+    where
+        F: FnMut(&str) -> Result<String, Error>,
+    {
+        match self {
+            Step::Command { args, stdin, only_if_exists, unless_exists, .. } => {
+                for arg in args {
+                    *arg = f(arg)?;
+                }
+                if let Some(stdin) = stdin {
+                    *stdin = f(stdin)?;
+                }
+                for path in only_if_exists.iter_mut().chain(unless_exists) {
+                    *path = f(path)?;
+                }
+            }
+            Step::File { path, content, .. } => {
+                *path = f(path)?;
+                *content = f(content)?;
+            }
+            Step::Http { url, body, .. } => {
+                *url = f(url)?;
+                if let Some(body) = body {
+                    *body = f(body)?;
+                }
+            }
+            Step::Script { path, .. } => {
+                *path = f(path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The command that runs a script step.
+    async fn script_args(
+        path: &std::path::Path,
+        interpreter: &Option<Vec<String>>,
+    ) -> Result<Vec<String>, Error> {
+        let mut args = match interpreter {
+            Some(interpreter) => interpreter.clone(),
+            None => {
+                let script = tokio::fs::read(path).await?;
+                let first_line = script.split(|&byte| byte == b'\n').next().unwrap_or_default();
+                match String::from_utf8_lossy(first_line).strip_prefix("#!") {
+                    Some(shebang) => shebang.split_whitespace().map(String::from).collect(),
+                    None => vec!["sh".to_string()],
+                }
+            }
+        };
+        // Absolute, so the script is still found from an isolated job's directory
+        args.push(std::path::absolute(path)?.to_string_lossy().into_owned());
+        Ok(args)
+    }
+
+    async fn run_command(
+        args: &[String],
+        reading: Reading,
+        env: &HashMap<String, String>,
+        stdin: Option<String>,
+        limits: &Limits,
+        index: usize,
+        tracker: &StepTracker,
+    ) -> Result<(), Error> {
+        let mut merged_env = tracker.env.clone();
+        merged_env.extend(env.clone());
+        let command: Vec<&String> = tracker.task_tracker.command_wrapper.iter()
+            .chain(args.iter())
+            .collect();
+        let mut process = tokio::process::Command::new(command[0]);
+        process
+            .args(&command[1..])
+            .envs(&merged_env)
+            .stdin(match stdin {
+                Some(_) => std::process::Stdio::piped(),
+                None => std::process::Stdio::null(),
+            })
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::piped())
+            .kill_on_drop(true);
+        if let Some(dir) = &tracker.task_tracker.working_dir {
+            process.current_dir(dir);
+        }
+        limits.apply(&mut process);
+        let mut child = process
+            .spawn()
+            .map_err(|e| match e.kind() {
+                std::io::ErrorKind::NotFound => Error::ProgramNotFound(command[0].clone()),
+                _ => Error::Io(e),
+            })?;
+        tracker.modify(index, |step| {
+            if let StepStatus::Command { attempts, .. } | StepStatus::Script { attempts, .. } = step {
+                *attempts += 1;
+            }
+        });
+
+        // Feed stdin alongside reading the output, then close it
+        let stdin_writer = match (child.stdin.take(), stdin) {
+            (Some(mut pipe), Some(content)) => Some(tokio::spawn(async move {
+                pipe.write_all(content.as_bytes()).await
+            })),
+            _ => None,
+        };
+
+        let stdout = child.stdout.take().unwrap();
+        let stdout_reader = Step::read_output(stdout, Stream::Stdout, reading, index, tracker.clone());
+        let stderr = child.stderr.take().unwrap();
+        let stderr_reader = Step::read_output(stderr, Stream::Stderr, reading, index, tracker.clone());
+
+        let status = child.wait().await?;
+        // Drain the remaining output before reporting the result
+        stdout_reader.await??;
+        stderr_reader.await??;
+        if let Some(stdin_writer) = stdin_writer {
+            // A command that exits without reading all of its input is fine
+            let _ = stdin_writer.await?;
+        }
+        if status.success() {
+            Ok(())
+        } else {
+            Err(Error::Exit(status))
+        }
+    }
+
+    /// Log each line read from a child's output stream, replacing any bytes
+    /// that aren't valid UTF-8. A read error stops logging with a warning
+    /// rather than failing the step, and the rest of the stream is discarded
+    /// so the child isn't blocked writing to it.
+    fn read_output<R>(
+        output: R,
+        stream: Stream,
+        reading: Reading,
+        index: usize,
+        tracker: StepTracker,
+    ) -> JoinHandle<Result<(), Error>>
+    where
+        R: tokio::io::AsyncRead + Unpin + Send + 'static,
+    {
+        tokio::spawn(async move {
+            let mut reader = tokio::io::BufReader::with_capacity(reading.buffer_size, output);
+            let read = if reading.chunked {
+                Step::read_chunks(&mut reader, stream, index, &tracker).await?
+            } else {
+                Step::read_lines(&mut reader, stream, index, &tracker).await?
+            };
+            if let Err(e) = read {
+                tracing::warn!(
+                    job = %tracker.task_tracker.job_name,
+                    task = %tracker.task_name,
+                    step = %tracker.label(index),
+                    error = %e,
+                    "Stopped reading step output",
+                );
+                let _ = tokio::io::copy(&mut reader, &mut tokio::io::sink()).await;
+            }
+            Ok(())
+        })
+    }
+
+    /// Log the output a line at a time. Failing to log is an error, while
+    /// failing to read is handed back to be dealt with.
+    async fn read_lines<R>(
+        reader: &mut tokio::io::BufReader<R>,
+        stream: Stream,
+        index: usize,
+        tracker: &StepTracker,
+    ) -> Result<std::io::Result<()>, Error>
+    where
+        R: tokio::io::AsyncRead + Unpin,
+    {
+        let mut buffer = Vec::new();
+        loop {
+            match reader.read_until(b'\n', &mut buffer).await {
+                Ok(0) => return Ok(Ok(())),
+                Ok(_) => tracker.log(index, stream, &String::from_utf8_lossy(&buffer))?,
+                Err(e) => return Ok(Err(e)),
+            }
+            buffer.clear();
+        }
+    }
+
+    /// Like `read_lines`, but take whatever the reader has buffered at a time
+    /// and log the complete lines in it together, holding back a partial line
+    /// until it's done.
+    async fn read_chunks<R>(
+        reader: &mut tokio::io::BufReader<R>,
+        stream: Stream,
+        index: usize,
+        tracker: &StepTracker,
+    ) -> Result<std::io::Result<()>, Error>
+    where
+        R: tokio::io::AsyncRead + Unpin,
+    {
+        let mut partial = Vec::new();
+        loop {
+            let chunk = match reader.fill_buf().await {
+                Ok(chunk) => chunk,
+                Err(e) => return Ok(Err(e)),
+            };
+            if chunk.is_empty() {
+                break;
+            }
+            let size = chunk.len();
+            let Some(end) = chunk.iter().rposition(|&byte| byte == b'\n') else {
+                partial.extend_from_slice(chunk);
+                reader.consume(size);
+                continue;
+            };
+            partial.extend_from_slice(&chunk[..=end]);
+            reader.consume(end + 1);
+
+            let text = String::from_utf8_lossy(&partial);
+            let lines: Vec<&str> = text.split_inclusive('\n').collect();
+            tracker.log_lines(index, stream, &lines)?;
+            partial.clear();
+        }
+        if !partial.is_empty() {
+            tracker.log(index, stream, &String::from_utf8_lossy(&partial))?;
+        }
+        Ok(Ok(()))
+    }
+
+    async fn write_file(path: &std::path::Path, content: &str, mode: Option<u32>) -> Result<(), Error> {
+        tokio::fs::write(path, content).await?;
+
+        #[cfg(unix)]
+        if let Some(mode) = mode {
+            use std::os::unix::fs::PermissionsExt;
+            let permissions = std::fs::Permissions::from_mode(mode);
+            tokio::fs::set_permissions(path, permissions).await?;
+        }
+        #[cfg(not(unix))]
+        let _ = mode;
+
+        Ok(())
+    }
+
+    async fn request(
+        method: &str,
+        url: &str,
+        body: Option<String>,
+        index: usize,
+        tracker: &StepTracker,
+    ) -> Result<(), Error> {
+        let method = reqwest::Method::from_bytes(method.to_uppercase().as_bytes())
+            .map_err(|_| Error::InvalidMethod(method.to_string()))?;
+        let mut request = reqwest::Client::new().request(method, url);
+        if let Some(body) = body {
+            request = request.body(body);
+        }
+
+        let response = request.send().await?;
+        let code = response.status().as_u16();
+        tracker.modify(index, |step| {
+            if let StepStatus::Http { status_code, .. } = step {
+                *status_code = Some(code);
+            }
+        });
+
+        if response.status().is_success() {
+            Ok(())
+        } else {
+            Err(Error::HttpStatus(url.to_string(), code))
+        }
+    }
+}
+
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum StepStatus {
+    Command{
+        /// Position of the step in its task, which updates are matched on
+        #[serde(default)]
+        index: usize,
+        #[serde(default)]
+        name: Option<String>,
+        args: Vec<String>,
+        output: Vec<String>,
+        /// Lines dropped from the start of `output` to stay within the limit
+        #[serde(default)]
+        truncated: usize,
+        /// Every line the command wrote to stdout, untouched, when its task
+        /// is one that others fan out over
+        #[serde(skip)]
+        items: Vec<String>,
+        #[serde(default)]
+        progress: f32,
+        /// How many times the command has been spawned
+        #[serde(default)]
+        attempts: u32,
+        status: Status,
+        #[serde(default, with = "humantime_serde")]
+        started_at: Option<SystemTime>,
+        #[serde(default, with = "humantime_serde")]
+        finished_at: Option<SystemTime>,
+        #[serde(default)]
+        duration_ms: Option<u64>,
+    },
+    File{
+        /// Position of the step in its task, which updates are matched on
+        #[serde(default)]
+        index: usize,
+        path: String,
+        status: Status,
+        #[serde(default, with = "humantime_serde")]
+        started_at: Option<SystemTime>,
+        #[serde(default, with = "humantime_serde")]
+        finished_at: Option<SystemTime>,
+        #[serde(default)]
+        duration_ms: Option<u64>,
+    },
+    Http{
+        /// Position of the step in its task, which updates are matched on
+        #[serde(default)]
+        index: usize,
+        method: String,
+        url: String,
+        status_code: Option<u16>,
+        status: Status,
+        #[serde(default, with = "humantime_serde")]
+        started_at: Option<SystemTime>,
+        #[serde(default, with = "humantime_serde")]
+        finished_at: Option<SystemTime>,
+        #[serde(default)]
+        duration_ms: Option<u64>,
+    },
+    Script{
+        /// Position of the step in its task, which updates are matched on
+        #[serde(default)]
+        index: usize,
+        path: String,
+        output: Vec<String>,
+        #[serde(default)]
+        truncated: usize,
+        #[serde(skip)]
+        items: Vec<String>,
+        #[serde(default)]
+        progress: f32,
+        #[serde(default)]
+        attempts: u32,
+        status: Status,
+        #[serde(default, with = "humantime_serde")]
+        started_at: Option<SystemTime>,
+        #[serde(default, with = "humantime_serde")]
+        finished_at: Option<SystemTime>,
+        #[serde(default)]
+        duration_ms: Option<u64>,
+    },
+}
+
+impl StepStatus {
+    /// The status of `step`, the one at `index` in its task, before it runs.
+    pub fn new(index: usize, step: &Step) -> StepStatus {
+        match step {
+            Step::Command { name, args, .. } => StepStatus::Command {
+                index,
+                name: name.clone(),
+                args: args.clone(),
+                output: Vec::new(),
+                truncated: 0,
+                items: Vec::new(),
+                progress: 0.0,
+                attempts: 0,
+                status: Status::Pending,
+                started_at: None,
+                finished_at: None,
+                duration_ms: None,
+            },
+            Step::File { path, .. } => StepStatus::File {
+                index,
+                path: path.clone(),
+                status: Status::Pending,
+                started_at: None,
+                finished_at: None,
+                duration_ms: None,
+            },
+            Step::Http { method, url, .. } => StepStatus::Http {
+                index,
+                method: method.clone(),
+                url: url.clone(),
+                status_code: None,
+                status: Status::Pending,
+                started_at: None,
+                finished_at: None,
+                duration_ms: None,
+            },
+            Step::Script { path, .. } => StepStatus::Script {
+                index,
+                path: path.clone(),
+                output: Vec::new(),
+                truncated: 0,
+                items: Vec::new(),
+                progress: 0.0,
+                attempts: 0,
+                status: Status::Pending,
+                started_at: None,
+                finished_at: None,
+                duration_ms: None,
+            },
+        }
+    }
+
+    pub fn duration_ms(&self) -> Option<u64> {
+        match self {
+            StepStatus::Command { duration_ms, .. } => *duration_ms,
+            StepStatus::File { duration_ms, .. } => *duration_ms,
+            StepStatus::Script { duration_ms, .. } => *duration_ms,
+            StepStatus::Http { duration_ms, .. } => *duration_ms,
+        }
+    }
+
+    pub fn index(&self) -> usize {
+        match self {
+            StepStatus::Command { index, .. } => *index,
+            StepStatus::File { index, .. } => *index,
+            StepStatus::Script { index, .. } => *index,
+            StepStatus::Http { index, .. } => *index,
+        }
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        match self {
+            StepStatus::Command { name, .. } => name.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// Clear what the step recorded when it last ran, apart from attempts.
+    pub fn reset(&mut self) {
+        match self {
+            StepStatus::Command { output, truncated, items, progress, .. }
+            | StepStatus::Script { output, truncated, items, progress, .. } => {
+                output.clear();
+                items.clear();
+                *truncated = 0;
+                *progress = 0.0;
+            }
+            StepStatus::Http { status_code, .. } => *status_code = None,
+            StepStatus::File { .. } => {}
+        }
+        let (status, started_at, finished_at, duration_ms) = self.times();
+        *status = Status::Pending;
+        *started_at = None;
+        *finished_at = None;
+        *duration_ms = None;
+    }
+
+    pub fn status(&self) -> &Status {
+        match self {
+            StepStatus::Command { status, .. } => status,
+            StepStatus::File { status, .. } => status,
+            StepStatus::Script { status, .. } => status,
+            StepStatus::Http { status, .. } => status,
+        }
+    }
+
+    pub fn set_status(&mut self, new_status: Status, now: SystemTime) {
+        let (status, started_at, finished_at, duration_ms) = self.times();
+        new_status.stamp(now, started_at, finished_at, duration_ms);
+        *status = new_status;
+    }
+
+    /// The status and timing fields every kind of step has.
+    fn times(&mut self) -> (
+        &mut Status,
+        &mut Option<SystemTime>,
+        &mut Option<SystemTime>,
+        &mut Option<u64>,
+    ) {
+        match self {
+            StepStatus::Command { status, started_at, finished_at, duration_ms, .. } => {
+                (status, started_at, finished_at, duration_ms)
+            }
+            StepStatus::File { status, started_at, finished_at, duration_ms, .. } => {
+                (status, started_at, finished_at, duration_ms)
+            }
+            StepStatus::Script { status, started_at, finished_at, duration_ms, .. } => {
+                (status, started_at, finished_at, duration_ms)
+            }
+            StepStatus::Http { status, started_at, finished_at, duration_ms, .. } => {
+                (status, started_at, finished_at, duration_ms)
+            }
+        }
+    }
+}
+
+
+#[derive(Clone)]
+pub struct StepTracker {
+    task_name: String,
+    task_tracker: TaskTracker,
+    env: HashMap<String, String>,
+    /// Keep the raw stdout lines of the task's steps for `for_each`
+    collect_items: bool,
+}
+
+impl StepTracker {
+    pub fn new(task_name: String, task_tracker: TaskTracker) -> StepTracker {
+        StepTracker {
+            task_name,
+            env: task_tracker.env.clone(),
+            task_tracker,
+            collect_items: false,
+        }
+    }
+
+    /// Replace `${steps.<step>.output}` references in `text` with the
+    /// captured output of that earlier step in the same task, all lines
+    /// joined with trailing whitespace trimmed. `<step>` is the step's name
+    /// or its index. Referring to a step that hasn't completed before step
+    /// `index` is an error.
+    pub fn expand_outputs(&self, index: usize, text: &str) -> Result<String, Error> {
+        let mut expanded = String::new();
+        let mut rest = text;
+
+        while let Some(start) = rest.find("${steps.") {
+            let end = match rest[start..].find('}') {
+                Some(end) => start + end,
+                None => break,
+            };
+            let reference = &rest[start..=end];
+            let invalid = |reason: &str| {
+                Error::InvalidReference(format!(
+                    "{}/{}: {} ({})",
+                    self.task_tracker.job_name, self.task_name, reference, reason,
+                ))
+            };
+
+            let step = match reference["${steps.".len()..reference.len() - 1].strip_suffix(".output") {
+                Some(step) => step,
+                None => return Err(invalid("expected ${steps.<step>.output}")),
+            };
+            let steps = match self.task_tracker.get(&self.task_name) {
+                Some(task) => task.steps,
+                None => Vec::new(),
+            };
+            let named = steps.iter().find(|status| status.name() == Some(step)).map(StepStatus::index);
+            let step = match named.or_else(|| step.parse::<usize>().ok()) {
+                Some(step) if step < index => step,
+                _ => return Err(invalid("not an earlier step")),
+            };
+            let output = match steps.into_iter().find(|status| status.index() == step) {
+                Some(StepStatus::Command { output, status, .. } | StepStatus::Script { output, status, .. })
+                    if status == Status::Finished || status == Status::Failed => output,
+                Some(StepStatus::Command { .. } | StepStatus::Script { .. }) => {
+                    return Err(invalid("step has not run"))
+                }
+                _ => return Err(invalid("step has no output")),
+            };
+
+            expanded.push_str(&rest[..start]);
+            expanded.push_str(output.concat().trim_end());
+            rest = &rest[end + 1..];
+        }
+
+        expanded.push_str(rest);
+        Ok(expanded)
+    }
+
+    /// Replace `${needs.<job>.outputs.<key>}` references in `text` with the
+    /// outputs the job set. `<job>` must be one this job depends on, so it
+    /// has finished and its outputs are final by the time any step here
+    /// starts; referring to any other job, or a key it never set, is an error.
+    pub fn expand_needs(&self, text: &str) -> Result<String, Error> {
+        let mut expanded = String::new();
+        let mut rest = text;
+
+        while let Some(start) = rest.find("${needs.") {
+            let end = match rest[start..].find('}') {
+                Some(end) => start + end,
+                None => break,
+            };
+            let reference = &rest[start..=end];
+            let invalid = |reason: &str| {
+                Error::InvalidReference(format!(
+                    "{}/{}: {} ({})",
+                    self.task_tracker.job_name, self.task_name, reference, reason,
+                ))
+            };
+
+            let (job, key) = match reference["${needs.".len()..reference.len() - 1].split_once(".outputs.") {
+                Some(parts) => parts,
+                None => return Err(invalid("expected ${needs.<job>.outputs.<key>}")),
+            };
+            let depends = match self.task_tracker.job_tracker.get(&self.task_tracker.job_name) {
+                Some(status) => status.depends,
+                None => Vec::new(),
+            };
+            if !depends.iter().any(|name| name == job) {
+                return Err(invalid("not a dependency"));
+            }
+            let value = match self.task_tracker.job_tracker.get(job) {
+                Some(status) => status.outputs.get(key).cloned(),
+                None => None,
+            };
+            let Some(value) = value else {
+                return Err(invalid("no such output"));
+            };
+
+            expanded.push_str(&rest[..start]);
+            expanded.push_str(&value);
+            rest = &rest[end + 1..];
+        }
+
+        expanded.push_str(rest);
+        Ok(expanded)
+    }
+
+    pub fn now(&self) -> SystemTime {
+        self.task_tracker.now()
+    }
+
+    /// The step's name if it has one, otherwise its index.
+    pub fn label(&self, index: usize) -> String {
+        // Read just the name, as this runs for every line a step logs
+        let name = self.task_tracker.job_tracker.inspect(&self.task_tracker.job_name, |job| {
+            job.tasks.iter()
+                .chain(&job.hooks)
+                .find(|task| task.name == self.task_name)
+                .and_then(|task| task.steps.iter().find(|step| step.index() == index))
+                .and_then(StepStatus::name)
+                .map(str::to_string)
+        });
+        match name.flatten() {
+            Some(name) => name,
+            None => index.to_string(),
+        }
+    }
+
+    pub fn get(&self, index: usize) -> Option<StepStatus> {
+        match self.task_tracker.get(&self.task_name) {
+            Some(task) => task.steps.into_iter().find(|step| step.index() == index),
+            None => None,
+        }
+    }
+
+    pub fn log(&self, index: usize, stream: Stream, message: &str) -> Result<(), Error> {
+        self.log_lines(index, stream, &[message])
+    }
+
+    /// Log lines read together from one stream, recording them all at once
+    /// rather than line by line.
+    pub fn log_lines(&self, index: usize, stream: Stream, lines: &[&str]) -> Result<(), Error> {
+        // The lines that are output, as written and with secrets hidden
+        let mut kept: Vec<(&str, String)> = Vec::new();
+        for &raw in lines {
+            // Hide secrets before the line goes anywhere, outputs included;
+            // only the items kept for `for_each` are left as written
+            let mut masked = raw.to_string();
+            for secret in &self.task_tracker.secrets {
+                masked = masked.replace(secret.as_str(), "***");
+            }
+
+            // Progress reports update the step instead of being logged
+            if let Some(percent) = masked.trim_end()
+                .strip_prefix(self.task_tracker.progress_prefix.as_str())
+                .and_then(|percent| percent.trim().parse::<f32>().ok())
+            {
+                self.modify(index, |step| {
+                    if let StepStatus::Command { progress, .. } | StepStatus::Script { progress, .. } = step {
+                        *progress = percent.clamp(0.0, 100.0);
+                    }
+                });
+                continue;
+            }
+
+            // Outputs for dependent jobs are recorded on the job, not logged
+            if let Some((key, value)) = masked.trim_end().strip_prefix(OUTPUT_PREFIX)
+                .and_then(|output| output.split_once('='))
+            {
+                let (key, value) = (key.trim().to_string(), value.to_string());
+                self.task_tracker.job_tracker.modify(&self.task_tracker.job_name, |job| {
+                    job.outputs.insert(key, value);
+                });
+                continue;
+            }
+
+            kept.push((raw, masked));
+        }
+        if kept.is_empty() {
+            return Ok(());
+        }
+
+        if let Some(template) = &self.task_tracker.log_prefix {
+            let timestamp = humantime_serde::re::humantime::format_rfc3339_millis(self.now());
+            let prefix = template
+                .replace("{job}", &self.task_tracker.job_name)
+                .replace("{task}", &self.task_name)
+                .replace("{step}", &self.label(index))
+                .replace("{timestamp}", &timestamp.to_string());
+            let text: String = kept.iter().map(|(_, message)| format!("{}{}", prefix, message)).collect();
+            match stream {
+                Stream::Stdout => print!("{}", text),
+                Stream::Stderr => eprint!("{}", text),
+            }
+        } else if self.task_tracker.mirror_output {
+            // Pass the lines through untouched, on the stream they came from
+            let text: String = kept.iter().map(|(_, message)| message.as_str()).collect();
+            match stream {
+                Stream::Stdout => print!("{}", text),
+                Stream::Stderr => eprint!("{}", text),
+            }
+        } else if tracing::enabled!(tracing::Level::INFO) {
+            let label = self.label(index);
+            for (_, message) in &kept {
+                tracing::info!(
+                    job = %self.task_tracker.job_name,
+                    task = %self.task_name,
+                    step = %label,
+                    "{}",
+                    message.trim_end(),
+                );
+            }
+        }
+        let mut last_line = 0;
+        let max_output_lines = self.task_tracker.max_output_lines;
+        let collect_items = self.collect_items && stream == Stream::Stdout;
+        self.modify(index, |step| {
+            if let StepStatus::Command { output, truncated, items, .. }
+                | StepStatus::Script { output, truncated, items, .. } = step
+            {
+                // Items are kept as written, whatever happens to the output
+                if collect_items {
+                    items.extend(kept.iter().map(|(raw, _)| raw.to_string()));
+                }
+                output.extend(kept.iter().map(|(_, message)| message.clone()));
+                // Keep only the most recent lines, counting what was dropped
+                if let Some(excess) = max_output_lines.and_then(|max| output.len().checked_sub(max)) {
+                    output.drain(..excess);
+                    *truncated += excess;
+                }
+                last_line = *truncated + output.len();
+            }
+        });
+
+        if let Some(log_json) = &self.task_tracker.log_json {
+            let first_line = (last_line + 1).saturating_sub(kept.len());
+            let timestamp = self.now();
+            let mut json = Vec::new();
+            for (offset, (_, message)) in kept.iter().enumerate() {
+                let record = LogRecord {
+                    run_id: self.task_tracker.job_tracker.run_id(),
+                    job: self.task_tracker.job_name.clone(),
+                    task: self.task_name.clone(),
+                    step: index,
+                    line: first_line + offset,
+                    timestamp,
+                    message: message.trim_end().to_string(),
+                };
+                serde_json::to_writer(&mut json, &record)?;
+                json.push(b'\n');
+            }
+            log_json.lock().write_all(&json)?;
+        }
+
+        if let Some(log_dir) = &self.task_tracker.log_dir {
+            let dir = log_dir.join(&self.task_tracker.job_name).join(&self.task_name);
+            std::fs::create_dir_all(&dir)?;
+            let mut file = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(dir.join(format!("{}.log", index)))?;
+            let text: String = kept.iter().map(|(_, message)| message.as_str()).collect();
+            file.write_all(text.as_bytes())?;
+        }
+
+        Ok(())
+    }
+
+    pub fn modify<F>(&self, index: usize, f: F)
+    where
+        F: FnOnce(&mut StepStatus),
+    {
+        self.task_tracker.modify(&self.task_name, |task| {
+            // Matched on the step's own index rather than its position, so
+            // the statuses needn't line up with the steps one for one
+            if let Some(step) = task.steps.iter_mut().find(|step| step.index() == index) {
+                f(step);
+            }
+        });
+    }
+}
+
+
+/// Which of a command's output streams a line came from.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Task {
+    pub name: String,
+    /// What the task is for, shown by the API and `list`; not used to run it
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub depends: Vec<String>,
+    pub steps: Vec<Step>,
+    /// Run one copy of the task per non-blank line another task's commands
+    /// wrote to stdout, written as `${<task>.output}`. Each copy sees the
+    /// line as `${item}`. Stderr, progress and output lines aren't items.
+    #[serde(default)]
+    pub for_each: Option<String>,
+    /// Files the steps must create, checked to exist and be non-empty
+    #[serde(default)]
+    pub produces: Vec<String>,
+    /// Environment variables for the task's commands, overriding the job's
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    /// Times to run all the steps again after the task fails
+    #[serde(default)]
+    pub retries: u32,
+    /// Pause between a failure and the retry
+    #[serde(default)]
+    pub retry_delay_secs: Option<u64>,
+    #[serde(default)]
+    pub timeout_secs: Option<u64>,
+}
+
+impl Task {
+    /// Start building a task with the given name.
+    pub fn builder(name: impl Into<String>) -> TaskBuilder {
+        TaskBuilder { task: Task::new(name.into()) }
+    }
+
+    pub fn new(name: String) -> Task {
+        Task {
+            name,
+            description: None,
+            depends: Vec::new(),
+            steps: Vec::new(),
+            for_each: None,
+            produces: Vec::new(),
+            env: HashMap::new(),
+            retries: 0,
+            retry_delay_secs: None,
+            timeout_secs: None,
+        }
+    }
+
+    /// The task whose output `for_each` refers to, if it is set and valid.
+    pub fn producer(&self) -> Option<&str> {
+        self.for_each.as_deref()?
+            .strip_prefix("${")?
+            .strip_suffix(".output}")
+    }
+
+    /// Steps that can never start because they wait on each other, labelled
+    /// by name or else position.
+    fn step_cycle(&self, job_name: &str) -> Vec<String> {
+        let label = |index: usize| match self.steps[index].name() {
+            Some(name) => name.to_string(),
+            None => index.to_string(),
+        };
+        let graph: Vec<(String, Vec<String>)> = self.steps.iter().enumerate()
+            .map(|(index, step)| {
+                let depends = match step {
+                    Step::Command { depends: Some(depends), .. } => depends.clone(),
+                    _ if index > 0 => vec![label(index - 1)],
+                    _ => Vec::new(),
+                };
+                (label(index), depends)
+            })
+            .collect();
+        unorderable(&graph).into_iter()
+            .map(|name| format!("{}/{}/{}", job_name, self.name, name))
+            .collect()
+    }
+
+    pub fn ready(&self, finished: &[Task]) -> bool {
+        self.waiting_on(finished).is_empty()
+    }
+
+    /// The tasks this one depends on, or takes its items from, that aren't
+    /// in `finished` yet.
+    pub fn waiting_on(&self, finished: &[Task]) -> Vec<&str> {
+        self.depends.iter().map(String::as_str).chain(self.producer())
+            .filter(|name| !finished.iter().any(|task| task.name == *name))
+            .collect()
+    }
+
+    /// Run the task's steps, from the top again if they fail and the task
+    /// has retries left.
+    pub async fn run(&mut self, tracker: StepTracker) -> Result<(), Error> {
+        let task_tracker = tracker.task_tracker.clone();
+        let steps = self.steps.clone();
+        let mut attempt = 1;
+        loop {
+            task_tracker.modify(&self.name, |task| task.attempts = attempt);
+            let error = match self.run_once(tracker.clone()).await {
+                Ok(()) => return Ok(()),
+                Err(e) if attempt > self.retries => return Err(e),
+                Err(e) => e,
+            };
+            tracing::warn!(
+                job = %task_tracker.job_name,
+                task = %self.name,
+                attempt,
+                error = %error,
+                "Task failed, retrying",
+            );
+            if let Some(delay) = self.retry_delay_secs {
+                tokio::time::sleep(Duration::from_secs(delay)).await;
+            }
+
+            // Start over from the steps as written, with a clean status
+            self.steps = steps.clone();
+            task_tracker.modify(&self.name, |task| {
+                task.reset();
+                task.set_status(Status::Running, task_tracker.now());
+            });
+            attempt += 1;
+        }
+    }
+
+    async fn run_once(&mut self, tracker: StepTracker) -> Result<(), Error> {
+        let timeout = self.timeout_secs.map(Duration::from_secs);
+        let steps = async {
+            if self.for_each.is_some() {
+                self.fan_out(tracker.clone()).await
+            } else {
+                self.run_steps(tracker.clone()).await
+            }
+        };
+
+        let Some(timeout) = timeout else {
+            return steps.await;
+        };
+
+        match tokio::time::timeout(timeout, steps).await {
+            Ok(result) => result,
+            Err(_) => {
+                // Out of time, fail the step that was cut short
+                for index in 0..self.steps.len() {
+                    tracker.modify(index, |step| {
+                        if *step.status() == Status::Running {
+                            step.set_status(Status::Failed, tracker.now());
+                        }
+                    });
+                }
+                let name = format!("{}/{}", tracker.task_tracker.job_name, self.name);
+                Err(Error::Timeout(name))
+            }
+        }
+    }
+
+    async fn run_steps(&mut self, mut tracker: StepTracker) -> Result<(), Error> {
+        tracker.env.extend(self.env.clone());
+
+        // Steps can only wait for named steps in the same task
+        for name in self.steps.iter().flat_map(Step::depends) {
+            if !self.steps.iter().any(|step| step.name() == Some(name)) {
+                let name = format!("{}/{}/{}", tracker.task_tracker.job_name, self.name, name);
+                return Err(Error::MissingDependency(name));
+            }
+        }
+        let cycle = self.step_cycle(&tracker.task_tracker.job_name);
+        if !cycle.is_empty() {
+            return Err(Error::CircularDependency(cycle));
+        }
+
+        let steps = self.steps.clone();
+        let mut pending: Vec<usize> = (0..steps.len()).collect();
+        let mut running = Vec::new();
+        let mut finished = Vec::new();
+        let mut spawned = AbortOnDrop::default();
+
+        loop {
+            // Start every step whose dependencies have finished
+            let mut ready = Vec::new();
+            pending.retain(|&index| {
+                if steps[index].ready(index, &steps, &finished) {
+                    ready.push(index);
+                    false
+                } else {
+                    true
+                }
+            });
+            for index in ready {
+                let mut step = steps[index].clone();
+                step.substitute(|text| {
+                    tracker.expand_needs(text).and_then(|text| tracker.expand_outputs(index, &text))
+                })?;
+                let tracker = tracker.clone();
+                let handle = tokio::spawn(async move {
+                    step.run(index, tracker).await.map(|()| (index, step))
+                });
+                spawned.0.push(handle.abort_handle());
+                running.push(handle);
+            }
+
+            if running.is_empty() {
+                if pending.is_empty() {
+                    break;
+                }
+                let job_name = &tracker.task_tracker.job_name;
+                let names = pending.iter()
+                    .map(|&index| format!("{}/{}/{}", job_name, self.name, tracker.label(index)))
+                    .collect();
+                return Err(Error::Unsatisfiable(names));
+            }
+
+            let (done, position, _) = futures::future::select_all(running.iter_mut()).await;
+            running.remove(position);
+            let error = match done {
+                Ok(Ok((index, step))) => {
+                    self.steps[index] = step;
+                    finished.push(index);
+                    continue;
+                }
+                Ok(Err(e)) => e,
+                Err(e) => Error::Join(e),
+            };
+
+            // Stop the steps running alongside the one that failed
+            drop(spawned);
+            for index in 0..self.steps.len() {
+                tracker.modify(index, |step| {
+                    if *step.status() == Status::Running {
+                        step.set_status(Status::Failed, tracker.now());
+                    }
+                });
+            }
+            return Err(error);
+        }
+
+        // Make sure the steps actually produced what they promised
+        for path in &self.produces {
+            match tokio::fs::metadata(tracker.task_tracker.resolve(path)).await {
+                Ok(metadata) if !metadata.is_file() || metadata.len() > 0 => {}
+                _ => return Err(Error::MissingArtifact(path.clone())),
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn fan_out(&self, tracker: StepTracker) -> Result<(), Error> {
+        let task_tracker = tracker.task_tracker;
+        let producer = match self.producer() {
+            Some(producer) => producer,
+            None => {
+                let reference = self.for_each.clone().unwrap_or_default();
+                return Err(Error::InvalidReference(reference));
+            }
+        };
+        let producer = match task_tracker.get(producer) {
+            Some(producer) => producer,
+            None => {
+                let name = format!("{}/{}", task_tracker.job_name, producer);
+                return Err(Error::MissingDependency(name));
+            }
+        };
+
+        // Collect the items from the producer's stdout, one per line
+        let mut items = Vec::new();
+        for step in &producer.steps {
+            if let StepStatus::Command { items: lines, .. } | StepStatus::Script { items: lines, .. } = step {
+                for line in lines {
+                    let line = line.trim();
+                    if !line.is_empty() {
+                        items.push(line.to_string());
+                    }
+                }
+            }
+        }
+
+        let mut running = Vec::new();
+        for item in items {
+            // Create an instance of the task for this item
+            let mut task = self.clone();
+            task.name = format!("{}[{}]", self.name, item);
+            task.for_each = None;
+            for path in &mut task.produces {
+                *path = path.replace("${item}", &item);
+            }
+            for step in &mut task.steps {
+                step.substitute(|text| Ok(text.replace("${item}", &item)))?;
+            }
+            task_tracker.insert(TaskStatus::new(&task));
+
+            let task_tracker = task_tracker.clone();
+            running.push(tokio::spawn(async move {
+                let task_name = task.name.clone();
+                task_tracker.modify(&task_name, |task| {
+                    task.set_status(Status::Running, task_tracker.now());
+                });
+                let result = task.run_steps(StepTracker::new(task_name.clone(), task_tracker.clone())).await;
+                task_tracker.modify(&task_name, |task| {
+                    let status = match result {
+                        Ok(()) => Status::Finished,
+                        Err(_) => Status::Failed,
+                    };
+                    task.set_status(status, task_tracker.now());
+                });
+                result
+            }));
+        }
+
+        let _spawned = AbortOnDrop(running.iter().map(JoinHandle::abort_handle).collect());
+
+        // Let every instance finish before reporting the first failure
+        let mut result = Ok(());
+        for done in futures::future::join_all(running).await {
+            if let Err(e) = done.map_err(Error::Join).and_then(|done| done) {
+                if result.is_ok() {
+                    result = Err(e);
+                }
+            }
+        }
+        result
+    }
+}
+
+
+/// Builds a `Task` in code, to add to a job with `JobBuilder::task`.
+pub struct TaskBuilder {
+    task: Task,
+}
+
+impl TaskBuilder {
+    pub fn build(self) -> Task {
+        self.task
+    }
+
+    pub fn depends(mut self, name: impl Into<String>) -> TaskBuilder {
+        self.task.depends.push(name.into());
+        self
+    }
+
+    pub fn description(mut self, description: impl Into<String>) -> TaskBuilder {
+        self.task.description = Some(description.into());
+        self
+    }
+
+    pub fn env(mut self, key: impl Into<String>, value: impl Into<String>) -> TaskBuilder {
+        self.task.env.insert(key.into(), value.into());
+        self
+    }
+
+    pub fn for_each(mut self, reference: impl Into<String>) -> TaskBuilder {
+        self.task.for_each = Some(reference.into());
+        self
+    }
+
+    pub fn produces(mut self, path: impl Into<String>) -> TaskBuilder {
+        self.task.produces.push(path.into());
+        self
+    }
+
+    pub fn retries(mut self, retries: u32) -> TaskBuilder {
+        self.task.retries = retries;
+        self
+    }
+
+    pub fn retry_delay_secs(mut self, retry_delay_secs: u64) -> TaskBuilder {
+        self.task.retry_delay_secs = Some(retry_delay_secs);
+        self
+    }
+
+    pub fn step(mut self, step: Step) -> TaskBuilder {
+        self.task.steps.push(step);
+        self
+    }
+
+    pub fn timeout_secs(mut self, timeout_secs: u64) -> TaskBuilder {
+        self.task.timeout_secs = Some(timeout_secs);
+        self
+    }
+}
+
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct TaskStatus {
+    pub name: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub depends: Vec<String>,
+    pub steps: Vec<StepStatus>,
+    #[serde(default)]
+    pub status: Status,
+    /// Fraction of the steps that are done, from 0.0 to 1.0
+    #[serde(default)]
+    pub progress: f32,
+    /// Which run of the steps this is, counting retries
+    #[serde(default)]
+    pub attempts: u32,
+    #[serde(default, with = "humantime_serde")]
+    pub started_at: Option<SystemTime>,
+    #[serde(default, with = "humantime_serde")]
+    pub finished_at: Option<SystemTime>,
+    #[serde(default)]
+    pub duration_ms: Option<u64>,
+}
+
+impl TaskStatus {
+    pub fn new(task: &Task) -> TaskStatus {
+        TaskStatus {
+            name: task.name.clone(),
+            description: task.description.clone(),
+            depends: task.depends.clone(),
+            steps: task.steps.iter().enumerate()
+                .map(|(index, step)| StepStatus::new(index, step))
+                .collect(),
+            status: Status::Pending,
+            progress: 0.0,
+            attempts: 0,
+            started_at: None,
+            finished_at: None,
+            duration_ms: None,
+        }
+    }
+
+    pub fn set_status(&mut self, status: Status, now: SystemTime) {
+        status.stamp(now, &mut self.started_at, &mut self.finished_at, &mut self.duration_ms);
+        self.status = status;
+    }
+
+    /// Give a task that was cut short, and whichever of its steps were still
+    /// running, a final status.
+    fn interrupt(&mut self, status: Status, now: SystemTime) {
+        if self.status != Status::Running {
+            return;
+        }
+        for step in &mut self.steps {
+            if *step.status() == Status::Running {
+                step.set_status(status.clone(), now);
+            }
+        }
+        self.set_status(status, now);
+    }
+
+    pub fn reset(&mut self) {
+        for step in &mut self.steps {
+            step.reset();
+        }
+        self.status = Status::Pending;
+        self.progress = 0.0;
+        self.started_at = None;
+        self.finished_at = None;
+        self.duration_ms = None;
+    }
+
+    fn update_progress(&mut self) {
+        let done = self.steps.iter().filter(|step| step.status().is_done()).count();
+        self.progress = fraction(done, self.steps.len(), self.status.is_done());
+    }
+}
+
+
+#[derive(Clone)]
+pub struct TaskTracker {
+    job_name: String,
+    job_tracker: JobTracker,
+    artifact_dir: Option<PathBuf>,
+    /// Directory to run commands in and resolve relative paths against,
+    /// instead of our own
+    working_dir: Option<PathBuf>,
+    command_wrapper: Vec<String>,
+    env: HashMap<String, String>,
+    log_dir: Option<PathBuf>,
+    log_json: Option<Arc<Mutex<std::fs::File>>>,
+    log_prefix: Option<String>,
+    max_output_lines: Option<usize>,
+    mirror_output: bool,
+    progress_prefix: String,
+    secrets: Vec<String>,
+}
+
+impl TaskTracker {
+    pub fn new(job_name: String, job_tracker: JobTracker) -> TaskTracker {
+        TaskTracker {
+            job_name,
+            job_tracker,
+            artifact_dir: None,
+            working_dir: None,
+            command_wrapper: Vec::new(),
+            env: HashMap::new(),
+            log_dir: None,
+            log_json: None,
+            log_prefix: None,
+            max_output_lines: None,
+            mirror_output: false,
+            progress_prefix: DEFAULT_PROGRESS_PREFIX.to_string(),
+            secrets: Vec::new(),
+        }
+    }
+
+    /// `path` as seen from the directory commands run in.
+    pub fn resolve(&self, path: &str) -> PathBuf {
+        match &self.working_dir {
+            Some(dir) => dir.join(path),
+            None => PathBuf::from(path),
+        }
+    }
+
+    /// A task's status, or a hook's if no task has the name.
+    pub fn get(&self, name: &str) -> Option<TaskStatus> {
+        match self.job_tracker.get(&self.job_name) {
+            Some(job) => job
+                .tasks
+                .iter()
+                .chain(&job.hooks)
+                .find(|task| task.name == name)
+                .cloned(),
+            None => None,
+        }
+    }
+
+    pub fn now(&self) -> SystemTime {
+        self.job_tracker.now()
+    }
+
+    pub fn command_wrapper(&mut self, command_wrapper: Vec<String>) {
+        self.command_wrapper = command_wrapper;
+    }
+
+    /// Add a task's status, replacing any earlier one with the same name.
+    pub fn insert(&self, task: TaskStatus) {
+        self.job_tracker.modify(&self.job_name, |job| {
+            match job.tasks.iter_mut().find(|existing| existing.name == task.name) {
+                Some(existing) => *existing = task,
+                None => job.tasks.push(task),
+            }
+        });
+    }
+
+    pub fn insert_hook(&self, hook: TaskStatus) {
+        self.job_tracker.modify(&self.job_name, |job| {
+            job.hooks.push(hook);
+        });
+    }
+
+    pub fn modify<F>(&self, name: &str, f: F)
+    where
+        F: FnOnce(&mut TaskStatus),
     {
         self.job_tracker.modify(&self.job_name, |job| {
-            if let Some(task) = job.tasks.iter_mut().find(|task| task.name == name) {
+            let mut tasks = job.tasks.iter_mut().chain(&mut job.hooks);
+            if let Some(task) = tasks.find(|task| task.name == name) {
                 f(task);
             }
         });
     }
 }
+
+
+/// Parse a file of `KEY=VALUE` lines, skipping blank lines and comments.
+/// Values may be quoted, and lines may start with `export`.
+async fn read_env_file(path: &str, optional: bool) -> Result<HashMap<String, String>, Error> {
+    let contents = match tokio::fs::read_to_string(path).await {
+        Ok(contents) => contents,
+        Err(e) if optional && e.kind() == std::io::ErrorKind::NotFound => return Ok(HashMap::new()),
+        Err(e) => return Err(Error::EnvFile(path.to_string(), e.to_string())),
+    };
+
+    let mut env = HashMap::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let Some((key, value)) = line.split_once('=') else {
+            let reason = format!("line {}: expected KEY=VALUE", number + 1);
+            return Err(Error::EnvFile(path.to_string(), reason));
+        };
+        let value = value.trim();
+        let value = [('"', '"'), ('\'', '\'')].iter()
+            .find_map(|&(open, close)| value.strip_prefix(open)?.strip_suffix(close))
+            .unwrap_or(value);
+        env.insert(key.trim().to_string(), value.to_string());
+    }
+    Ok(env)
+}
+
+
+/// The share of `total` items that are `done`, treating an empty list as
+/// done once its owner is.
+fn fraction(done: usize, total: usize, owner_done: bool) -> f32 {
+    match total {
+        0 if owner_done => 1.0,
+        0 => 0.0,
+        total => done as f32 / total as f32,
+    }
+}
+
+
+/// Names in a dependency graph that are part of a cycle. Dependencies
+/// outside the graph are ignored.
+fn unorderable(graph: &[(String, Vec<String>)]) -> Vec<String> {
+    let mut nodes: HashMap<&str, Vec<usize>> = HashMap::new();
+    for (index, (name, _)) in graph.iter().enumerate() {
+        nodes.entry(name.as_str()).or_default().push(index);
+    }
+    // Each node's dependencies as positions in the graph
+    let edges: Vec<Vec<usize>> = graph.iter()
+        .map(|(_, depends)| depends.iter()
+            .flat_map(|name| nodes.get(name.as_str()).into_iter().flatten().copied())
+            .collect())
+        .collect();
+    let mut dependents: Vec<Vec<usize>> = vec![Vec::new(); graph.len()];
+    for (index, depends) in edges.iter().enumerate() {
+        for &depend in depends {
+            dependents[depend].push(index);
+        }
+    }
+
+    // Kahn's algorithm: take away nodes with nothing left to wait for, and
+    // whatever remains is stuck
+    let mut waiting: Vec<usize> = edges.iter().map(Vec::len).collect();
+    let mut ready: Vec<usize> = (0..graph.len()).filter(|&index| waiting[index] == 0).collect();
+    while let Some(index) = ready.pop() {
+        for &dependent in &dependents[index] {
+            waiting[dependent] -= 1;
+            if waiting[dependent] == 0 {
+                ready.push(dependent);
+            }
+        }
+    }
+
+    // Only some of what's stuck is on the cycle; peel off the nodes that are
+    // merely waiting downstream of it the same way, against the edges
+    let stuck: Vec<bool> = waiting.iter().map(|&count| count > 0).collect();
+    let mut waited_on: Vec<usize> = dependents.iter()
+        .map(|dependents| dependents.iter().filter(|&&dependent| stuck[dependent]).count())
+        .collect();
+    let mut peeled: Vec<usize> = (0..graph.len())
+        .filter(|&index| stuck[index] && waited_on[index] == 0)
+        .collect();
+    let mut cycle = stuck.clone();
+    while let Some(index) = peeled.pop() {
+        cycle[index] = false;
+        for &depend in &edges[index] {
+            if cycle[depend] {
+                waited_on[depend] -= 1;
+                if waited_on[depend] == 0 {
+                    peeled.push(depend);
+                }
+            }
+        }
+    }
+    graph.iter().zip(cycle)
+        .filter(|(_, on_cycle)| *on_cycle)
+        .map(|((name, _), _)| name.clone())
+        .collect()
+}
+
+
+/// Escape text for a Markdown table cell.
+fn escape_markdown(text: &str) -> String {
+    text.replace('|', "\\|")
+}
+
+
+/// Escape a Prometheus label value.
+fn escape_label(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+
+/// One or more jobs from YAML, see `Loader::load_str`.
+fn parse_yaml_jobs(yaml: &str) -> Result<Vec<Job>, Error> {
+    let jobs: Option<Vec<Job>> = serde_yml::from_str(&merge_documents(yaml))?;
+    Ok(jobs.unwrap_or_default())
+}
+
+/// A job, or an array of jobs, from JSON.
+fn parse_json_jobs(json: &str) -> Result<Vec<Job>, Error> {
+    let jobs = match serde_json::from_str::<serde_json::Value>(json)? {
+        serde_json::Value::Array(jobs) => {
+            jobs.into_iter().map(serde_json::from_value).collect::<Result<Vec<Job>, _>>()?
+        }
+        job => vec![serde_json::from_value(job)?],
+    };
+    Ok(jobs)
+}
+
+/// Read the tasks in the files at `paths`, relative to `dir`, after those of
+/// the files they include in turn. `including` is the chain of files that led
+/// here, so a file that ends up including itself is reported as a cycle.
+fn read_includes(
+    paths: &[String],
+    dir: &std::path::Path,
+    including: &mut Vec<PathBuf>,
+) -> Result<Vec<Task>, Error> {
+    let mut tasks = Vec::new();
+    for path in paths {
+        let path = std::fs::canonicalize(dir.join(path))
+            .map_err(|e| Error::InvalidReference(format!("include {}: {}", path, e)))?;
+        if let Some(start) = including.iter().position(|included| *included == path) {
+            let cycle = including[start..].iter().map(|path| path.display().to_string()).collect();
+            return Err(Error::CircularDependency(cycle));
+        }
+
+        let content = std::fs::read_to_string(&path)?;
+        let include: Include = if path.extension().is_some_and(|ext| ext == "json") {
+            serde_json::from_str(&content)?
+        } else {
+            serde_yml::from_str(&content)?
+        };
+        including.push(path.clone());
+        let parent = path.parent().unwrap_or(std::path::Path::new(""));
+        tasks.extend(read_includes(&include.include, parent, including)?);
+        including.pop();
+        tasks.extend(include.tasks);
+    }
+    Ok(tasks)
+}
+
+/// Rewrite a multi-document YAML stream as a single document holding a
+/// sequence of the original documents, so that anchors are shared between
+/// them. A document may start on its `---` line, as in `--- {name: a}`.
+/// Lines stay where they were, so errors still point at the right line.
+fn merge_documents(yaml: &str) -> String {
+    let mut merged = String::new();
+    let mut item_started = false;
+
+    for line in yaml.lines() {
+        let line = match line.strip_prefix("--- ") {
+            Some(rest) => {
+                item_started = false;
+                rest
+            }
+            None => line,
+        };
+        if line == "---" || line == "..." {
+            item_started = false;
+        } else if !item_started && !line.trim().is_empty() && !line.trim_start().starts_with('#') {
+            merged.push_str("- ");
+            merged.push_str(line);
+            item_started = true;
+        } else {
+            merged.push_str("  ");
+            merged.push_str(line);
+        }
+        merged.push('\n');
+    }
+
+    merged
+}
+
+
+/// Wait until `deadline`, or forever if there isn't one.
+async fn until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+
+/// Wait for the first of `running` to finish and remove it from the list.
+/// Returns `None` if `deadline` passes before anything finishes.
+async fn select_running<T>(
+    running: &mut Vec<JoinHandle<T>>,
+    deadline: Option<Instant>,
+) -> Option<Result<T, JoinError>> {
+    let select = futures::future::select_all(running.iter_mut());
+    let (done, index, _) = match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline, select).await.ok()?,
+        None => select.await,
+    };
+    running.remove(index);
+    Some(done)
+}
+
+
+/// Accept command arguments as a list, or as one string split by shell rules.
+fn deserialize_args<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Args {
+        List(Vec<String>),
+        Line(String),
+    }
+
+    match Args::deserialize(deserializer)? {
+        Args::List(args) => Ok(args),
+        Args::Line(line) => shell_words::split(&line).map_err(serde::de::Error::custom),
+    }
+}
+
+
+/// Turn a list of errors into a single result: the error itself when there
+/// is just one, or `Error::Multiple` when there are several.
+fn collect_errors(mut errors: Vec<Error>) -> Result<(), Error> {
+    match errors.len() {
+        0 => Ok(()),
+        1 => Err(errors.remove(0)),
+        _ => Err(Error::Multiple(errors)),
+    }
+}
+
+
+/// Copy a file, or a directory and everything in it, creating `to`'s parents.
+fn copy_path(from: &std::path::Path, to: &std::path::Path) -> std::io::Result<()> {
+    if let Some(parent) = to.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    if !from.is_dir() {
+        return std::fs::copy(from, to).map(|_| ());
+    }
+    std::fs::create_dir_all(to)?;
+    for entry in std::fs::read_dir(from)? {
+        let entry = entry?;
+        copy_path(&entry.path(), &to.join(entry.file_name()))?;
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sh(script: &str) -> Step {
+        Step::command(vec!["sh".to_string(), "-c".to_string(), script.to_string()])
+    }
+
+    fn job(name: &str, steps: Vec<Step>) -> Job {
+        let mut task = Task::builder("main");
+        for step in steps {
+            task = task.step(step);
+        }
+        Job::builder(name).task(task.build()).build()
+    }
+
+    async fn run(jobs: Vec<Job>) -> (Result<(), Error>, JobTracker) {
+        let mut runner = Runner::new();
+        runner.jobs = jobs;
+        let tracker = JobTracker::new();
+        let result = runner.run(tracker.clone()).await;
+        (result, tracker)
+    }
+
+    /// Every line of output kept for a task's steps.
+    fn output(tracker: &JobTracker, job: &str, task: &str) -> Vec<String> {
+        let task = TaskTracker::new(job.to_string(), tracker.clone()).get(task).unwrap();
+        task.steps.into_iter()
+            .flat_map(|step| match step {
+                StepStatus::Command { output, .. } | StepStatus::Script { output, .. } => output,
+                _ => Vec::new(),
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn failed_step_carries_exit_code() {
+        let (result, _) = run(vec![job("build", vec![sh("exit 3")])]).await;
+        match result {
+            Err(Error::StepFailed { status, .. }) => assert_eq!(status.code(), Some(3)),
+            other => panic!("expected a failed step, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn capture_on_failure_keeps_only_failed_output() {
+        let passing = Job::builder("passing")
+            .capture(Capture::OnFailure)
+            .task(Task::builder("main").step(sh("echo ok")).build())
+            .build();
+        let failing = Job::builder("failing")
+            .capture(Capture::OnFailure)
+            .task(Task::builder("main").step(sh("echo broken; exit 1")).build())
+            .build();
+
+        let (_, tracker) = run(vec![passing]).await;
+        assert!(output(&tracker, "passing", "main").is_empty());
+        let (_, tracker) = run(vec![failing]).await;
+        assert_eq!(output(&tracker, "failing", "main"), vec!["broken\n"]);
+    }
+
+    #[tokio::test]
+    async fn for_each_runs_a_task_per_line() {
+        let job = Job::builder("deploy")
+            .task(Task::builder("list").step(sh("printf 'a\\nb\\nc\\n'")).build())
+            .task(Task::builder("each").for_each("${list.output}").step(sh("echo ${item}")).build())
+            .build();
+
+        let (result, tracker) = run(vec![job]).await;
+        result.unwrap();
+        for item in ["a", "b", "c"] {
+            let name = format!("each[{}]", item);
+            let task = TaskTracker::new("deploy".to_string(), tracker.clone()).get(&name).unwrap();
+            assert_eq!(task.status, Status::Finished);
+            assert_eq!(output(&tracker, "deploy", &name), vec![format!("{}\n", item)]);
+        }
+        assert_eq!(tracker.get("deploy").unwrap().tasks.len(), 5);
+    }
+
+    #[tokio::test]
+    async fn for_each_items_ignore_stderr_and_output_policies() {
+        let job = Job::builder("deploy")
+            .capture(Capture::OnFailure)
+            .task(Task::builder("list").step(sh("echo warning >&2; printf 'a\\nsecret\\nc\\n'")).build())
+            .task(Task::builder("each").for_each("${list.output}").step(sh("true")).build())
+            .build();
+        let mut runner = Runner::new();
+        runner.jobs = vec![job];
+        runner.max_output_lines = Some(1);
+        runner.secrets = vec!["secret".to_string()];
+        let tracker = JobTracker::new();
+        runner.run(tracker.clone()).await.unwrap();
+
+        let names: Vec<String> = tracker.get("deploy").unwrap().tasks.into_iter().map(|task| task.name).collect();
+        assert_eq!(names, vec!["list", "each", "each[a]", "each[secret]", "each[c]"]);
+    }
+
+    #[tokio::test]
+    async fn always_runs_when_a_job_is_cancelled() {
+        let job = Job::builder("build")
+            .task(Task::builder("main").step(sh("sleep 10")).build())
+            .always(sh("echo $BED_JOB_STATUS"))
+            .build();
+        let mut runner = Runner::new();
+        runner.jobs = vec![job];
+        let tracker = JobTracker::new();
+        let run = tokio::spawn({
+            let tracker = tracker.clone();
+            async move {
+                let result = runner.run(tracker).await;
+                (runner, result)
+            }
+        });
+        while tracker.get("build").is_none_or(|job| job.status != Status::Running) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(tracker.cancel("build"));
+
+        let (runner, result) = run.await.unwrap();
+        assert!(matches!(result, Err(Error::Cancelled(_))));
+        assert_eq!(runner.jobs[0].tasks.len(), 1);
+        let job = tracker.get("build").unwrap();
+        assert_eq!(job.status, Status::Cancelled);
+        assert_eq!(job.tasks[0].status, Status::Cancelled);
+        assert_eq!(job.hooks[0].status, Status::Finished);
+        assert_eq!(output(&tracker, "build", "always"), vec!["cancelled\n"]);
+    }
+
+    #[tokio::test]
+    async fn always_runs_when_the_run_times_out() {
+        let job = Job::builder("build")
+            .task(Task::builder("main").step(sh("sleep 10")).build())
+            .always(sh("echo $BED_JOB_STATUS"))
+            .build();
+        let mut runner = Runner::new();
+        runner.jobs = vec![job];
+        runner.timeout_secs = Some(1);
+        let tracker = JobTracker::new();
+
+        let result = runner.run(tracker.clone()).await;
+        assert!(matches!(result, Err(Error::Timeout(ref name)) if name == "pipeline"), "{:?}", result);
+        let job = tracker.get("build").unwrap();
+        assert_eq!(job.status, Status::Failed);
+        assert_eq!(job.tasks[0].status, Status::Failed);
+        assert_eq!(output(&tracker, "build", "always"), vec!["failed\n"]);
+    }
+
+    #[tokio::test]
+    async fn definitions_come_back_when_a_run_stops_early() {
+        let slow = Job::builder("slow")
+            .task(Task::builder("wait").step(sh("sleep 10")).build())
+            .task(Task::builder("quick").step(sh("true")).build())
+            .task(Task::builder("after").depends("wait").step(sh("true")).build())
+            .build();
+        let mut runner = Runner::new();
+        runner.jobs = vec![slow, job("broken", vec![sh("sleep 0.2; exit 1")]), job("fine", vec![sh("true")])];
+        let tracker = JobTracker::new();
+
+        assert!(matches!(runner.run(tracker.clone()).await, Err(Error::StepFailed { .. })));
+        let names: Vec<&str> = runner.jobs.iter().map(|job| job.name.as_str()).collect();
+        assert_eq!(names, vec!["slow", "broken", "fine"]);
+        let tasks: Vec<&str> = runner.jobs[0].tasks.iter().map(|task| task.name.as_str()).collect();
+        assert_eq!(tasks, vec!["wait", "quick", "after"]);
+
+        let slow = tracker.get("slow").unwrap();
+        assert_eq!(slow.status, Status::Cancelled);
+        assert_eq!(slow.tasks[0].status, Status::Cancelled);
+        assert_eq!(slow.tasks[2].status, Status::Pending);
+        let (_, result) = runner.retry("broken", &tracker).unwrap().await.unwrap();
+        assert!(matches!(result, Err(Error::StepFailed { .. })));
+    }
+
+    #[tokio::test]
+    async fn secrets_are_masked_in_outputs() {
+        let mut runner = Runner::new();
+        runner.jobs = vec![job("build", vec![sh("echo ::output::token=hunter2")])];
+        runner.secrets = vec!["hunter2".to_string()];
+        let tracker = JobTracker::new();
+        runner.run(tracker.clone()).await.unwrap();
+        assert_eq!(tracker.get("build").unwrap().outputs["token"], "***");
+    }
+
+    #[tokio::test]
+    async fn isolated_scripts_are_found_in_the_work_dir() {
+        let task = Task::builder("main")
+            .step(Step::File { path: "hello.sh".to_string(), content: "echo hello\n".to_string(), mode: None })
+            .step(Step::Script { path: "hello.sh".to_string(), interpreter: None })
+            .build();
+        let job = Job::builder("build").isolate(true).task(task).build();
+        let (result, tracker) = run(vec![job]).await;
+        result.unwrap();
+        assert_eq!(output(&tracker, "build", "main"), vec!["hello\n"]);
+    }
+
+    #[tokio::test]
+    async fn log_records_carry_their_context() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("log.json");
+        let mut runner = Runner::new();
+        runner.jobs = vec![job("build", vec![sh("true"), sh("echo one; echo two")])];
+        runner.log_json = Some(path.clone());
+        let tracker = JobTracker::new();
+        let runs = RunStore::new(tracker.clone(), DEFAULT_RUN_HISTORY);
+        runs.start();
+        let id = runs.start();
+        runner.run(tracker).await.unwrap();
+
+        let records: Vec<LogRecord> = std::fs::read_to_string(&path).unwrap().lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(records.len(), 2);
+        let record = &records[1];
+        assert_eq!(record.run_id, Some(id));
+        assert_eq!((record.job.as_str(), record.task.as_str()), ("build", "main"));
+        assert_eq!((record.step, record.line), (1, 2));
+        assert_eq!(record.message, "two");
+    }
+
+    #[tokio::test]
+    async fn one_failure_is_reported_plainly() {
+        let mut runner = Runner::new();
+        runner.continue_on_error = true;
+        runner.jobs = vec![job("a", vec![sh("exit 1")]), job("b", vec![sh("true")])];
+        let result = runner.run(JobTracker::new()).await;
+        assert!(matches!(result, Err(Error::StepFailed { .. })), "{:?}", result);
+    }
+
+    #[tokio::test]
+    async fn several_failures_are_reported_together() {
+        let mut runner = Runner::new();
+        runner.continue_on_error = true;
+        runner.jobs = vec![job("a", vec![sh("exit 1")]), job("b", vec![sh("exit 2")])];
+        match runner.run(JobTracker::new()).await {
+            Err(Error::Multiple(errors)) => assert_eq!(errors.len(), 2),
+            other => panic!("expected several errors, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn progress_lines_update_the_step() {
+        let (result, tracker) = run(vec![job("build", vec![sh("echo ::progress::75; echo done")])]).await;
+        result.unwrap();
+        let task = TaskTracker::new("build".to_string(), tracker.clone()).get("main").unwrap();
+        match &task.steps[0] {
+            StepStatus::Command { progress, output, .. } => {
+                assert_eq!(*progress, 75.0);
+                assert_eq!(output, &vec!["done\n".to_string()]);
+            }
+            other => panic!("expected a command step, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn files_for_other_platforms_are_not_loaded() {
+        let mut loader = Loader::new(String::new());
+        loader.load_str("name: elsewhere\nbed:\n  os: [no-such-os]\ntasks: []\n").unwrap();
+        loader.load_str("name: here\ntasks: []\n").unwrap();
+        let names: Vec<&str> = loader.jobs.iter().map(|job| job.name.as_str()).collect();
+        assert_eq!(names, vec!["here"]);
+    }
+
+    #[test]
+    fn matrix_jobs_are_picked_out_by_name() {
+        let mut loader = Loader::new(String::new());
+        loader.load_str(concat!(
+            "name: build\nmatrix:\n  v: [a, b]\ntasks: []\n",
+            "---\nname: test\ndepends: [build]\ntasks: []\n",
+            "---\nname: docs\ntasks: []\n",
+        )).unwrap();
+
+        let mut runner = loader.runner();
+        runner.filter(&["build".to_string()]).unwrap();
+        let names: Vec<&str> = runner.jobs.iter().map(|job| job.name.as_str()).collect();
+        assert_eq!(names, vec!["build[a]", "build[b]"]);
+
+        let mut runner = loader.runner();
+        runner.skip(&["build".to_string()]);
+        let names: Vec<&str> = runner.jobs.iter().map(|job| job.name.as_str()).collect();
+        assert_eq!(names, vec!["docs"]);
+        assert_eq!(runner.skipped.len(), 3);
+    }
+
+    #[test]
+    fn duplicate_step_names_are_an_error() {
+        let result = Loader::new(String::new()).load_str(concat!(
+            "name: build\ntasks:\n  - name: main\n    steps:\n",
+            "      - {name: one, args: 'true'}\n      - {name: one, args: 'false'}\n",
+        ));
+        assert!(matches!(result, Err(Error::DuplicateStep(ref name)) if name == "build/main/one"), "{:?}", result);
+    }
+
+    #[test]
+    fn empty_matrix_is_an_error() {
+        let result = Loader::new(String::new()).load_str("name: build\nmatrix:\n  v: []\ntasks: []\n");
+        assert!(matches!(result, Err(Error::EmptyMatrix(ref key)) if key == "build.v"), "{:?}", result);
+    }
+
+    #[test]
+    fn documents_may_start_on_their_marker_line() {
+        let mut loader = Loader::new(String::new());
+        loader.load_str("--- {name: a, tasks: []}\n--- # b\nname: b\ntasks: []\n").unwrap();
+        let names: Vec<&str> = loader.jobs.iter().map(|job| job.name.as_str()).collect();
+        assert_eq!(names, vec!["a", "b"]);
+    }
+
+    #[test]
+    fn anchors_are_shared_between_documents() {
+        let mut loader = Loader::new(String::new());
+        loader.load_str(concat!(
+            "name: a\ntasks:\n  - name: main\n    steps:\n      - &hello {args: 'echo hello'}\n",
+            "---\nname: b\ntasks:\n  - name: main\n    steps:\n      - *hello\n",
+        )).unwrap();
+        match &loader.jobs[1].tasks[0].steps[0] {
+            Step::Command { args, .. } => assert_eq!(args, &vec!["echo", "hello"]),
+            other => panic!("expected a command step, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn missing_product_fails_the_task() {
+        let job = Job::builder("build")
+            .task(Task::builder("main").step(sh("true")).produces("no-such-file").build())
+            .build();
+        let (result, _) = run(vec![job]).await;
+        assert!(matches!(result, Err(Error::MissingArtifact(ref path)) if path == "no-such-file"));
+    }
+
+    #[tokio::test]
+    async fn invalid_utf8_output_is_kept() {
+        let (result, tracker) = run(vec![job("build", vec![sh("printf '\\377ok\\n'")])]).await;
+        result.unwrap();
+        assert_eq!(output(&tracker, "build", "main"), vec!["\u{FFFD}ok\n"]);
+    }
+
+    #[tokio::test]
+    async fn final_line_without_newline_is_kept() {
+        let (result, tracker) = run(vec![job("build", vec![sh("echo first; printf last")])]).await;
+        result.unwrap();
+        assert_eq!(output(&tracker, "build", "main"), vec!["first\n", "last"]);
+    }
+
+    #[tokio::test]
+    async fn high_volume_output_is_kept_whole() {
+        let (result, tracker) = run(vec![job("build", vec![sh("seq 1 50000")])]).await;
+        result.unwrap();
+        let output = output(&tracker, "build", "main");
+        assert_eq!(output.len(), 50000);
+        assert!(output.iter().enumerate().all(|(index, line)| *line == format!("{}\n", index + 1)));
+    }
+
+    #[tokio::test]
+    async fn tiny_buffers_still_read_everything() {
+        for buffer_size in [0, 1] {
+            let mut step = sh("seq 1 100");
+            if let Step::Command { buffer_size: size, .. } = &mut step {
+                *size = Some(buffer_size);
+            }
+            let (result, tracker) = run(vec![job("build", vec![step])]).await;
+            result.unwrap();
+            assert_eq!(output(&tracker, "build", "main").len(), 100);
+        }
+    }
+
+    #[tokio::test]
+    async fn empty_command_is_an_error() {
+        let (result, _) = run(vec![job("build", vec![Step::command(Vec::new())])]).await;
+        assert!(matches!(result, Err(Error::EmptyCommand(_))), "{:?}", result);
+    }
+
+    #[test]
+    fn malformed_json_is_an_error() {
+        let result = Loader::new(String::new()).load_json_str("{\"name\": \"build\",");
+        assert!(matches!(result, Err(Error::SerdeJson(_))), "{:?}", result);
+    }
+
+    #[test]
+    fn topo_order_breaks_ties_by_name() {
+        let mut runner = Runner::new();
+        runner.jobs = vec![
+            Job::builder("deploy").depends("test").depends("package").build(),
+            Job::builder("test").depends("build").build(),
+            Job::builder("package").depends("build").build(),
+            Job::builder("build").build(),
+            Job::builder("docs").build(),
+        ];
+        assert_eq!(runner.topo_order().unwrap(), vec!["build", "docs", "package", "test", "deploy"]);
+
+        runner.jobs.push(Job::builder("a").depends("b").build());
+        runner.jobs.push(Job::builder("b").depends("a").build());
+        assert!(matches!(runner.topo_order(), Err(Error::CircularDependency(_))));
+    }
+
+    #[tokio::test]
+    async fn reset_clears_a_finished_job() {
+        let (result, tracker) = run(vec![job("build", vec![sh("echo hello")])]).await;
+        result.unwrap();
+        let mut status = tracker.get("build").unwrap();
+        status.reset();
+
+        assert_eq!(status.status, Status::Pending);
+        assert!(status.started_at.is_none() && status.duration_ms.is_none());
+        let task = &status.tasks[0];
+        assert_eq!(task.status, Status::Pending);
+        match &task.steps[0] {
+            StepStatus::Command { output, status, attempts, .. } => {
+                assert!(output.is_empty());
+                assert_eq!(*status, Status::Pending);
+                assert_eq!(*attempts, 1);
+            }
+            other => panic!("expected a command step, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn cycle_is_reported_up_front() {
+        let jobs = vec![
+            Job::builder("a").depends("b").task(Task::builder("main").step(sh("true")).build()).build(),
+            Job::builder("b").depends("a").task(Task::builder("main").step(sh("true")).build()).build(),
+        ];
+        let (result, tracker) = run(jobs).await;
+        assert!(matches!(result, Err(Error::CircularDependency(_))), "{:?}", result);
+        assert!(tracker.list().is_empty());
+    }
+
+    #[tokio::test]
+    async fn failed_dependency_skips_dependents() {
+        let mut dependent = job("test", vec![sh("true")]);
+        dependent.depends("build".to_string());
+        let (result, tracker) = run(vec![job("build", vec![sh("exit 1")]), dependent]).await;
+
+        assert!(matches!(result, Err(Error::StepFailed { .. })), "{:?}", result);
+        let test = tracker.get("test").unwrap();
+        assert_eq!(test.status, Status::Skipped);
+        assert_eq!(test.reason.as_deref(), Some("Dependency build failed"));
+    }
+
+    #[test]
+    fn only_the_cycle_is_reported() {
+        let graph = vec![
+            ("build".to_string(), Vec::new()),
+            ("a".to_string(), vec!["build".to_string(), "b".to_string()]),
+            ("b".to_string(), vec!["a".to_string()]),
+            ("deploy".to_string(), vec!["a".to_string()]),
+        ];
+        assert_eq!(unorderable(&graph), vec!["a", "b"]);
+    }
+
+    #[test]
+    fn long_dependency_chain_is_ordered() {
+        // Declared last first, so every job waits on one further down the list
+        let mut runner = Runner::new();
+        runner.jobs = (0..5000).rev()
+            .map(|index| match index {
+                0 => Job::builder("job0").build(),
+                _ => Job::builder(format!("job{}", index)).depends(format!("job{}", index - 1)).build(),
+            })
+            .collect();
+
+        runner.validate().unwrap();
+        let order = runner.topo_order().unwrap();
+        assert_eq!(order.len(), 5000);
+        assert_eq!((order[0].as_str(), order[4999].as_str()), ("job0", "job4999"));
+    }
+
+    #[tokio::test]
+    async fn capture_on_failure_keeps_output_of_a_step_allowed_to_fail() {
+        let mut allowed = sh("echo flaky; exit 1");
+        if let Step::Command { continue_on_error, .. } = &mut allowed {
+            *continue_on_error = true;
+        }
+        let job = Job::builder("build")
+            .capture(Capture::OnFailure)
+            .task(Task::builder("main").step(allowed).step(sh("echo ok")).build())
+            .build();
+
+        let (result, tracker) = run(vec![job]).await;
+        result.unwrap();
+        assert_eq!(output(&tracker, "build", "main"), vec!["flaky\n"]);
+    }
+
+    #[tokio::test]
+    async fn command_wrapper_prefixes_commands() {
+        let mut runner = Runner::new();
+        runner.command_wrapper = vec!["echo".to_string(), "wrapped  by".to_string()];
+        runner.jobs = vec![job("build", vec![sh("exit 1")])];
+        let tracker = JobTracker::new();
+        runner.run(tracker.clone()).await.unwrap();
+        assert_eq!(output(&tracker, "build", "main"), vec!["wrapped  by sh -c exit 1\n"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn task_timeout_fails_the_task() {
+        let job = Job::builder("build")
+            .task(Task::builder("main").step(sh("sleep 60")).timeout_secs(5).build())
+            .build();
+        let started = Instant::now();
+        let (result, tracker) = run(vec![job]).await;
+
+        assert!(matches!(result, Err(Error::Timeout(ref name)) if name == "build/main"), "{:?}", result);
+        assert_eq!(started.elapsed(), Duration::from_secs(5));
+        let task = TaskTracker::new("build".to_string(), tracker.clone()).get("main").unwrap();
+        assert_eq!(task.status, Status::Failed);
+        assert_eq!(*task.steps[0].status(), Status::Failed);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn job_timeout_fails_the_job() {
+        let job = Job::builder("build")
+            .timeout_secs(5)
+            .task(Task::builder("main").step(sh("sleep 60")).build())
+            .build();
+        let started = Instant::now();
+        let (result, tracker) = run(vec![job]).await;
+
+        assert!(matches!(result, Err(Error::Timeout(ref name)) if name == "build"), "{:?}", result);
+        assert_eq!(started.elapsed(), Duration::from_secs(5));
+        let job = tracker.get("build").unwrap();
+        assert_eq!(job.status, Status::Failed);
+        assert_eq!(job.tasks[0].status, Status::Failed);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn task_retries_wait_between_attempts() {
+        let job = Job::builder("build")
+            .task(Task::builder("main").step(sh("exit 1")).retries(2).retry_delay_secs(30).build())
+            .build();
+        let started = Instant::now();
+        let (result, tracker) = run(vec![job]).await;
+
+        assert!(matches!(result, Err(Error::StepFailed { .. })), "{:?}", result);
+        // Two waits between three attempts, and no real time spent on them
+        assert_eq!(started.elapsed(), Duration::from_secs(60));
+        let task = TaskTracker::new("build".to_string(), tracker.clone()).get("main").unwrap();
+        assert_eq!(task.attempts, 3);
+    }
+
+    #[tokio::test]
+    async fn stopping_a_run_cancels_its_jobs() {
+        let slow = Job::builder("slow")
+            .task(Task::builder("main").step(sh("sleep 10")).build())
+            .always(sh("echo $BED_JOB_STATUS"))
+            .build();
+        let mut later = job("later", vec![sh("true")]);
+        later.depends("slow".to_string());
+        let mut runner = Runner::new();
+        runner.jobs = vec![slow, later];
+        let tracker = JobTracker::new();
+
+        let stop = tokio::time::sleep(Duration::from_millis(100));
+        let result = runner.run_until(tracker.clone(), stop).await;
+        assert!(matches!(result, Err(Error::Cancelled(ref name)) if name == "pipeline"), "{:?}", result);
+        let slow = tracker.get("slow").unwrap();
+        assert_eq!(slow.status, Status::Cancelled);
+        assert_eq!(output(&tracker, "slow", "always"), vec!["cancelled\n"]);
+        assert_eq!(tracker.get("later").unwrap().status, Status::Pending);
+        assert_eq!(runner.jobs.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn chunked_reading_keeps_every_line() {
+        for buffer_size in [1, 7, 64 * 1024] {
+            let mut step = sh("seq 1 50000; printf 'no newline'");
+            if let Step::Command { buffer_size: size, chunked, .. } = &mut step {
+                *size = Some(buffer_size);
+                *chunked = true;
+            }
+            let (result, tracker) = run(vec![job("build", vec![step])]).await;
+            result.unwrap();
+
+            let mut expected: Vec<String> = (1..=50000).map(|line| format!("{}\n", line)).collect();
+            expected.push("no newline".to_string());
+            assert_eq!(output(&tracker, "build", "main"), expected, "buffer size {}", buffer_size);
+        }
+    }
+
+    #[tokio::test]
+    async fn chunked_log_records_are_numbered_by_line() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("log.json");
+        let mut step = sh("echo one; echo ::progress::50; echo two; echo three");
+        if let Step::Command { chunked, .. } = &mut step {
+            *chunked = true;
+        }
+        let mut runner = Runner::new();
+        runner.jobs = vec![job("build", vec![step])];
+        runner.log_json = Some(path.clone());
+        runner.run(JobTracker::new()).await.unwrap();
+
+        let records: Vec<(usize, String)> = std::fs::read_to_string(&path).unwrap().lines()
+            .map(|line| serde_json::from_str::<LogRecord>(line).unwrap())
+            .map(|record| (record.line, record.message))
+            .collect();
+        let expected = [(1, "one"), (2, "two"), (3, "three")].map(|(line, message)| (line, message.to_string()));
+        assert_eq!(records, expected);
+    }
+
+    /// Serve a mock comments API that answers with `status`, handing over
+    /// each request's path, headers and body.
+    async fn mock_api(
+        status: axum::http::StatusCode,
+    ) -> (String, tokio::sync::mpsc::UnboundedReceiver<(String, axum::http::HeaderMap, String)>) {
+        let (sender, received) = tokio::sync::mpsc::unbounded_channel();
+        let comment = move |uri: axum::http::Uri, headers: axum::http::HeaderMap, body: String| async move {
+            sender.send((uri.path().to_string(), headers, body)).unwrap();
+            status
+        };
+        let app = axum::Router::new().fallback(axum::routing::post(comment));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        (url, received)
+    }
+
+    #[tokio::test]
+    async fn reporter_comments_on_a_github_pull_request() {
+        let (api_url, mut received) = mock_api(axum::http::StatusCode::CREATED).await;
+        let mut failing = job("deploy", vec![sh("exit 1")]);
+        failing.depends("build".to_string());
+        let (_, tracker) = run(vec![job("build", vec![sh("true")]), failing]).await;
+
+        let vars = HashMap::from([
+            ("GITHUB_API_URL", api_url.as_str()),
+            ("GITHUB_REPOSITORY", "owner/repo"),
+            ("GITHUB_REF", "refs/pull/7/merge"),
+            ("GITHUB_TOKEN", "hunter2"),
+        ]);
+        let reporter = Reporter::from_vars(|name| vars.get(name).map(|value| value.to_string())).unwrap();
+        reporter.post(&tracker).await.unwrap();
+
+        let (path, headers, body) = received.recv().await.unwrap();
+        assert_eq!(path, "/repos/owner/repo/issues/7/comments");
+        assert_eq!(headers["authorization"], "Bearer hunter2");
+        assert_eq!(headers["content-type"], "application/json");
+        let payload: serde_json::Value = serde_json::from_str(&body).unwrap();
+        let keys: Vec<&String> = payload.as_object().unwrap().keys().collect();
+        assert_eq!(keys, vec!["body"]);
+
+        let comment = payload["body"].as_str().unwrap();
+        let lines: Vec<&str> = comment.lines().collect();
+        assert_eq!(lines[..4], [
+            "**bed**: 2 jobs: 1 finished, 1 failed (deploy)",
+            "",
+            "| Job | Task | Status | Duration |",
+            "| --- | --- | --- | --- |",
+        ]);
+        assert!(lines[4].starts_with("| build | | finished | ") && lines[4].ends_with("s |"), "{}", comment);
+        assert!(lines[5].starts_with("| build | main | finished | "), "{}", comment);
+        assert!(lines[6].starts_with("| deploy | | failed | "), "{}", comment);
+        assert!(lines[7].starts_with("| deploy | main | failed | "), "{}", comment);
+        assert_eq!(lines.len(), 8);
+    }
+
+    #[tokio::test]
+    async fn reporter_comments_on_a_gitlab_merge_request() {
+        let (api_url, mut received) = mock_api(axum::http::StatusCode::UNAUTHORIZED).await;
+        let (_, tracker) = run(vec![job("build", vec![sh("true")])]).await;
+
+        let vars = HashMap::from([
+            ("CI_API_V4_URL", api_url.as_str()),
+            ("CI_PROJECT_ID", "42"),
+            ("CI_MERGE_REQUEST_IID", "3"),
+            ("BED_REPORT_TOKEN", "hunter2"),
+        ]);
+        let reporter = Reporter::from_vars(|name| vars.get(name).map(|value| value.to_string())).unwrap();
+        let result = reporter.post(&tracker).await;
+        assert!(matches!(result, Err(Error::HttpStatus(_, 401))), "{:?}", result);
+
+        let (path, headers, body) = received.recv().await.unwrap();
+        assert_eq!(path, "/projects/42/merge_requests/3/notes");
+        assert_eq!(headers["private-token"], "hunter2");
+        let payload: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert!(payload["body"].as_str().unwrap().starts_with("**bed**: 1 job: 1 finished\n"));
+    }
+
+    #[test]
+    fn reporter_needs_a_pull_request_and_a_token() {
+        let reporter = |vars: &[(&str, &str)]| {
+            let vars: HashMap<&str, &str> = vars.iter().copied().collect();
+            Reporter::from_vars(|name| vars.get(name).map(|value| value.to_string()))
+        };
+        // A push to a branch rather than a pull request
+        assert!(reporter(&[("GITHUB_REPOSITORY", "o/r"), ("GITHUB_REF", "refs/heads/main"), ("GITHUB_TOKEN", "t")]).is_none());
+        assert!(reporter(&[("GITHUB_REPOSITORY", "o/r"), ("BED_PULL_REQUEST", "5")]).is_none());
+        assert!(reporter(&[("CI_PROJECT_ID", "1"), ("GITLAB_TOKEN", "t")]).is_none());
+        assert!(reporter(&[]).is_none());
+
+        let github = reporter(&[("GITHUB_REPOSITORY", "o/r"), ("BED_PULL_REQUEST", "5"), ("GITHUB_TOKEN", "t")]);
+        assert_eq!(github.unwrap().pull_request, PullRequest::GitHub {
+            api_url: "https://api.github.com".to_string(),
+            repository: "o/r".to_string(),
+            number: 5,
+        });
+    }
+}
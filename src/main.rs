@@ -1,38 +1,660 @@
-use axum::{extract::Path, routing::get, Json, Router};
-use bed::{Loader, JobTracker};
-use clap::Parser;
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::http::{header, HeaderValue, Method, StatusCode};
+use axum::extract::{Path, Query, Request};
+use axum::middleware::{self, Next};
+use axum::response::{Html, IntoResponse, Response};
+use axum::{routing::{get, post}, Json, Router};
+use bed::{Job, JobStatus, JobTracker, Loader, PipelineStatus, Reporter, RunStore, Runner, Status, Step, StepTracker, Task, TaskTracker};
+use clap::{Parser, Subcommand};
+use serde::Serialize;
+use notify::Watcher;
+use std::collections::HashMap;
+use std::io::IsTerminal;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use parking_lot::Mutex;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{mpsc, watch};
+use tower_http::cors::{AllowHeaders, AllowOrigin, CorsLayer};
+use tracing_subscriber::EnvFilter;
 
-#[derive(Parser)]
+/// The page served at / with `--ui`, built on the JSON and WebSocket routes.
+const DASHBOARD: &str = include_str!("dashboard.html");
+
+// Every option is global, so it can come before or after the subcommand
+#[derive(Clone, Parser)]
 struct Args {
-    #[clap(short, long, default_value = ".bed")]
+    #[clap(short, long, default_value = ".bed", global = true)]
     directory: String,
+    /// Load just this pipeline file instead of the directory
+    #[clap(short, long, conflicts_with = "directory", global = true)]
+    file: Option<PathBuf>,
+    /// Load the files matching this glob pattern as well as the directory;
+    /// may be given more than once
+    #[clap(long, global = true)]
+    glob: Vec<String>,
+    /// Prefix every command with this program and arguments, e.g. "timeout 600"
+    #[clap(long, global = true)]
+    command_wrapper: Option<String>,
+    /// Keep running independent jobs after a job fails
+    #[clap(long, global = true)]
+    continue_on_error: bool,
+    /// Copy each job's artifacts to <ARTIFACT_DIR>/<job>/ once it succeeds
+    #[clap(long, global = true)]
+    artifact_dir: Option<PathBuf>,
+    /// Emit logs as JSON lines, filtered by RUST_LOG
+    #[clap(long, global = true)]
+    json_logs: bool,
+    /// Write each step's output to <LOG_DIR>/<job>/<task>/<step>.log
+    #[clap(long, global = true)]
+    log_dir: Option<PathBuf>,
+    /// Print command output directly, starting each line with this template,
+    /// e.g. "{timestamp} {job}/{task}: "; {step} is also filled in
+    #[clap(long, global = true)]
+    log_prefix: Option<String>,
+    /// Append every line of step output to this file as a JSON record
+    #[clap(long, global = true)]
+    log_json: Option<PathBuf>,
+    /// Write a JUnit XML report of the run to this file once it completes
+    #[clap(long, global = true)]
+    junit: Option<PathBuf>,
+    /// After `bed run`, comment with its summary on the GitHub pull request
+    /// or GitLab merge request named by the CI environment, posting with
+    /// BED_REPORT_TOKEN, GITHUB_TOKEN or GITLAB_TOKEN
+    #[clap(long, global = true)]
+    comment: bool,
+    /// Cancel the run if it takes longer than this many seconds
+    #[clap(long, global = true)]
+    timeout: Option<u64>,
+    /// Number of past runs to keep when serving, besides the current one
+    #[clap(long, default_value_t = bed::DEFAULT_RUN_HISTORY, global = true)]
+    keep_runs: usize,
+    /// Keep at most this many lines of output per step in memory; log files
+    /// still get everything
+    #[clap(long, global = true)]
+    max_output_lines: Option<usize>,
+    /// Pass command output through to stdout and stderr instead of logging it
+    #[clap(long, global = true)]
+    mirror_output: bool,
+    /// Run only these jobs and the jobs they depend on
+    #[clap(long, value_delimiter = ',', global = true)]
+    only: Vec<String>,
+    /// Run only the jobs with this tag and the jobs they depend on; applied
+    /// before --only
+    #[clap(long, global = true)]
+    tag: Option<String>,
+    /// Leave these jobs out and skip everything that depends on them; applied
+    /// after --only, so it can trim the selected jobs' dependencies
+    #[clap(long, value_delimiter = ',', global = true)]
+    skip: Vec<String>,
+    /// Output lines starting with this prefix set the step's progress percentage
+    #[clap(long, default_value = bed::DEFAULT_PROGRESS_PREFIX, global = true)]
+    progress_prefix: String,
+    /// Mask the values of these environment variables in command output
+    #[clap(long, value_delimiter = ',', global = true)]
+    secret_env: Vec<String>,
+    /// Let browsers on this origin call the API, e.g. "http://localhost:8080",
+    /// or "*" for any; may be given more than once
+    #[clap(long, value_parser = HeaderValue::from_str, global = true)]
+    cors_origin: Vec<HeaderValue>,
+    /// Require "Authorization: Bearer <TOKEN>" on every API request
+    #[clap(long, env = "BED_AUTH_TOKEN", hide_env_values = true, global = true)]
+    auth_token: Option<String>,
+    /// Serve a dashboard of the pipeline at / when serving
+    #[clap(long, global = true)]
+    ui: bool,
+    /// Re-run the pipeline whenever its directory or file changes, cancelling any
+    /// run in progress; only applies when serving
+    #[clap(long, global = true)]
+    watch: bool,
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    error: String,
+}
+
+#[derive(Serialize)]
+struct RunBody {
+    id: u64,
+}
+
+#[derive(Clone, Subcommand)]
+enum Command {
+    /// Run a single command through bed without any pipeline files
+    Exec {
+        #[clap(required = true, last = true)]
+        args: Vec<String>,
+    },
+    /// Print each job with its dependencies and tasks, without running anything
+    List,
+    /// Run the pipeline once, print a summary and exit non-zero on failure
+    Run,
+    /// Run the pipeline and serve its status over HTTP (the default)
+    Serve,
 }
 
 #[tokio::main]
-async fn main() -> Result<(), bed::Error> {
-    let args = Args::parse();
-    let mut loader = Loader::new(args.directory);
+async fn main() -> ExitCode {
+    let mut args = Args::parse();
+
+    let filter = EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| EnvFilter::new("info"));
+    let subscriber = tracing_subscriber::fmt().with_env_filter(filter);
+    if args.json_logs {
+        subscriber.json().init();
+    } else {
+        subscriber.init();
+    }
+
+    // Split like a shell would, so a wrapper argument can be quoted
+    let command_wrapper: Vec<String> = match args.command_wrapper.as_deref().map(shell_words::split) {
+        Some(Ok(wrapper)) => wrapper,
+        Some(Err(e)) => {
+            eprintln!("bed: invalid --command-wrapper: {}", e);
+            return ExitCode::FAILURE;
+        }
+        None => Vec::new(),
+    };
+
+    let result = match args.command.take() {
+        Some(Command::Exec { args: command }) => exec(command, args, command_wrapper).await,
+        Some(Command::List) => list(args, command_wrapper),
+        Some(Command::Run) => run(args, command_wrapper).await,
+        Some(Command::Serve) | None => serve(args, command_wrapper).await,
+    };
+
+    // Return rather than exit so the runtime shuts down and kills any
+    // commands that are still running
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("bed: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn exec(command: Vec<String>, args: Args, command_wrapper: Vec<String>) -> Result<(), bed::Error> {
+    // Build a synthetic single-step job so the step is tracked like any other
+    let mut step = Step::command(command);
+    let job = Job::builder("exec")
+        .task(Task::builder("exec").step(step.clone()).build())
+        .build();
+
+    let tracker = JobTracker::new();
+    tracker.insert(JobStatus::new(&job));
+    let runner = configure(Runner::new(), &args, command_wrapper);
+    let task_tracker = runner.task_tracker(job.name.clone(), &tracker)?;
+    let step_tracker = StepTracker::new(job.tasks[0].name.clone(), task_tracker);
+
+    let result = match args.timeout {
+        Some(secs) => tokio::time::timeout(Duration::from_secs(secs), step.run(0, step_tracker))
+            .await
+            .unwrap_or_else(|_| Err(bed::Error::Timeout("exec".to_string()))),
+        None => step.run(0, step_tracker).await,
+    };
+    match result {
+        Ok(()) => Ok(()),
+        Err(bed::Error::StepFailed { status, .. }) => std::process::exit(status.code().unwrap_or(1)),
+        Err(e) => Err(e),
+    }
+}
+
+/// Load the pipeline and configure a runner from the command line.
+fn load(args: Args, command_wrapper: Vec<String>) -> Result<Runner, bed::Error> {
+    let mut loader = match &args.file {
+        Some(file) => Loader::from_file(file.clone()),
+        None => Loader::new(args.directory.clone()),
+    };
+    for pattern in &args.glob {
+        loader.add_glob(pattern.clone());
+    }
+    loader.load()?;
+    let mut runner = configure(loader.runner(), &args, command_wrapper);
+    if let Some(tag) = &args.tag {
+        runner.filter_by_tag(tag)?;
+    }
+    if !args.only.is_empty() {
+        runner.filter(&args.only)?;
+    }
+    runner.skip(&args.skip);
+    Ok(runner)
+}
+
+/// Set the runner's options from the command line.
+fn configure(mut runner: Runner, args: &Args, command_wrapper: Vec<String>) -> Runner {
+    runner.artifact_dir = args.artifact_dir.clone();
+    runner.command_wrapper = command_wrapper;
+    runner.continue_on_error = args.continue_on_error;
+    runner.log_dir = args.log_dir.clone();
+    runner.log_json = args.log_json.clone();
+    runner.max_output_lines = args.max_output_lines;
+    runner.mirror_output = args.mirror_output;
+    runner.log_prefix = args.log_prefix.clone();
+    runner.progress_prefix = args.progress_prefix.clone();
+    runner.secrets = args.secret_env.iter().filter_map(|name| std::env::var(name).ok()).collect();
+    runner.timeout_secs = args.timeout;
+    runner
+}
+
+fn list(args: Args, command_wrapper: Vec<String>) -> Result<(), bed::Error> {
+    let runner = load(args, command_wrapper)?;
+    let skipped = runner.skipped.iter().map(|job| (job, " [skipped]"));
+    for (job, note) in runner.jobs.iter().map(|job| (job, "")).chain(skipped) {
+        let tags: String = job.tags.iter().map(|tag| format!(" #{}", tag)).collect();
+        println!("{}{}{}{}{}", job.name, depends_on(&job.depends), tags, note, described(&job.description));
+        for (index, task) in job.tasks.iter().enumerate() {
+            let branch = if index + 1 == job.tasks.len() { "└──" } else { "├──" };
+            println!("{} {}{}{}", branch, task.name, depends_on(&task.depends), described(&task.description));
+        }
+    }
+    Ok(())
+}
+
+/// ` - <description>` if there is one.
+fn described(description: &Option<String>) -> String {
+    match description {
+        Some(description) => format!(" - {}", description),
+        None => String::new(),
+    }
+}
+
+/// ` (after a, b)` for a non-empty list of dependencies.
+fn depends_on(depends: &[String]) -> String {
+    if depends.is_empty() {
+        String::new()
+    } else {
+        format!(" (after {})", depends.join(", "))
+    }
+}
+
+async fn run(args: Args, command_wrapper: Vec<String>) -> Result<(), bed::Error> {
+    let junit = args.junit.clone();
+    let comment = args.comment;
+    let mut runner = load(args, command_wrapper)?;
+    let names: Vec<String> = runner.jobs.iter()
+        .chain(&runner.skipped)
+        .map(|job| job.name.clone())
+        .collect();
+    let tracker = JobTracker::new();
+
+    let result = runner.run(tracker.clone()).await;
+
+    for name in names {
+        let Some(job) = tracker.get(&name) else {
+            continue;
+        };
+        match (job.duration_ms, job.reason) {
+            (Some(ms), _) => println!("{:<24} {:?} ({}.{:03}s)", name, job.status, ms / 1000, ms % 1000),
+            (None, Some(reason)) => println!("{:<24} {:?} ({})", name, job.status, reason),
+            (None, None) => println!("{:<24} {:?}", name, job.status),
+        }
+    }
+
+    if let Some(path) = junit {
+        std::fs::write(path, tracker.to_junit_xml())?;
+    }
+
+    print_summary(&tracker);
+    if comment {
+        // The run's result stands whether or not the comment gets through
+        match Reporter::from_env() {
+            Some(reporter) => {
+                if let Err(e) = reporter.post(&tracker).await {
+                    tracing::warn!(error = %e, "Failed to post the summary comment");
+                }
+            }
+            None => tracing::warn!("No pull request or token to comment with"),
+        }
+    }
+    result
+}
+
+/// Print how many jobs ended up in each status to stderr, colored by the
+/// outcome on a terminal.
+fn print_summary(tracker: &JobTracker) {
+    let summary = tracker.summary();
+    if std::io::stderr().is_terminal() {
+        // Red for failures, yellow for anything else short of finishing
+        let color = if summary.failed > 0 {
+            31
+        } else if summary.finished < summary.total {
+            33
+        } else {
+            32
+        };
+        eprintln!("\x1b[{}m{}\x1b[0m", color, summary);
+    } else {
+        eprintln!("{}", summary);
+    }
+}
+
+/// Run the pipeline once in the background of the server, until a reload
+/// comes through `reloads`.
+async fn build(
+    mut runner: Runner,
+    tracker: JobTracker,
+    junit: Option<PathBuf>,
+    mut reloads: watch::Receiver<()>,
+) -> Result<(), bed::Error> {
+    let reloaded = async move {
+        // Only a reload stops the run, not the watcher going away
+        if reloads.changed().await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    };
+    let result = runner.run_until(tracker.clone(), reloaded).await;
+    if let Some(path) = junit {
+        std::fs::write(path, tracker.to_junit_xml())?;
+    }
+    print_summary(&tracker);
+    // Keep serving so the failure can be inspected, but say so right away
+    match &result {
+        Err(bed::Error::Cancelled(_)) => tracing::info!("Build cancelled"),
+        Err(e) => tracing::error!(error = %e, "Build failed"),
+        Ok(()) => {}
+    }
+    result
+}
+
+async fn serve(args: Args, command_wrapper: Vec<String>) -> Result<(), bed::Error> {
     let tracker = JobTracker::new();
-    let tracker_clone = tracker.clone();
+    let runs = RunStore::new(tracker.clone(), args.keep_runs);
+
+    let runner = load(args.clone(), command_wrapper.clone())?;
+    // Keep the job definitions around so jobs can be retried and the whole
+    // pipeline run again later
+    let retry_runner = Arc::new(Mutex::new(runner.clone()));
+
+    // Reloading cancels the run in progress, letting its jobs run their hooks
+    let reload = Arc::new(watch::channel(()).0);
+    runs.start();
+    let build_future = tokio::spawn(build(runner, tracker.clone(), args.junit.clone(), reload.subscribe()));
+    let build_future = Arc::new(tokio::sync::Mutex::new(build_future));
+
+    if args.watch {
+        let watched = match &args.file {
+            Some(file) => file.clone(),
+            None => PathBuf::from(&args.directory),
+        };
+        let (watcher, mut changes) = watch(&watched)?;
+        let build_future = build_future.clone();
+        let tracker = tracker.clone();
+        let retry_runner = retry_runner.clone();
+        let runs = runs.clone();
+        let args = args.clone();
+        let reload = reload.clone();
+        tokio::spawn(async move {
+            let _watcher = watcher;
+            while next_change(&mut changes).await {
+                // Cancel the current run, which kills its commands, wait for
+                // it to wind down and start over
+                let mut build_future = build_future.lock().await;
+                reload.send_replace(());
+                let _ = (&mut *build_future).await;
+                let runner = match load(args.clone(), command_wrapper.clone()) {
+                    Ok(runner) => runner,
+                    Err(e) => {
+                        tracing::error!(error = %e, "Reload failed");
+                        continue;
+                    }
+                };
+                tracing::info!("Pipeline changed, running again");
+                *retry_runner.lock() = runner.clone();
+                runs.start();
+                let reloads = reload.subscribe();
+                *build_future = tokio::spawn(build(runner, tracker.clone(), args.junit.clone(), reloads));
+            }
+        });
+    }
+
+    let run_tracker = tracker.clone();
+    let run_runner = retry_runner.clone();
+    let run_future = build_future.clone();
+    let run_store = runs.clone();
+    let run_reload = reload.clone();
+    let run_pipeline = || async move {
+        // Busy while a run, a reload or a retried job is still going
+        let running = run_tracker.list().iter().any(|job| job.status == Status::Running);
+        let mut build_future = match run_future.try_lock() {
+            Ok(build_future) if build_future.is_finished() && !running => build_future,
+            _ => {
+                let error = "A run is already in progress".to_string();
+                return Err((StatusCode::CONFLICT, Json(ErrorBody { error })));
+            }
+        };
+        let runner = run_runner.lock().clone();
+        let id = run_store.start();
+        *build_future = tokio::spawn(build(runner, run_tracker, args.junit.clone(), run_reload.subscribe()));
+        Ok((StatusCode::ACCEPTED, Json(RunBody { id })))
+    };
+
+    let metrics_tracker = tracker.clone();
+    let get_metrics = || async move {
+        ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], metrics_tracker.to_metrics())
+    };
+
+    let list_store = runs.clone();
+    let list_runs = || async move { Json(list_store.list()) };
+
+    let get_run = |id: Path<u64>| async move {
+        match runs.get(*id) {
+            Some(run) => Ok(Json(run)),
+            None => Err(not_found(format!("Run not found: {}", *id))),
+        }
+    };
+
+    let retry_tracker = tracker.clone();
+    let retry_job = |name: Path<String>| async move {
+        if retry_tracker.get(&name).is_none() {
+            return Err(not_found(format!("Job not found: {}", name.as_str())));
+        }
+        let retry = retry_runner.lock().retry(&name, &retry_tracker);
+        match retry {
+            Ok(_) => Ok((StatusCode::ACCEPTED, Json(retry_tracker.get(&name)))),
+            Err(e @ bed::Error::JobNotReady(_)) => Err(error(StatusCode::CONFLICT, e)),
+            Err(e) => Err(error(StatusCode::INTERNAL_SERVER_ERROR, e)),
+        }
+    };
+
+    let cancel_tracker = tracker.clone();
+    let cancel_job = |name: Path<String>| async move {
+        if !cancel_tracker.cancel(&name) {
+            return Err(not_found(format!("Job not running: {}", name.as_str())));
+        }
+        Ok((StatusCode::ACCEPTED, Json(cancel_tracker.get(&name))))
+    };
+
+    let ws_tracker = tracker.clone();
+    let ws = |upgrade: WebSocketUpgrade| async move {
+        upgrade.on_upgrade(move |socket| stream_updates(socket, ws_tracker))
+    };
+
+    let task_tracker = tracker.clone();
+    let get_task = |Path((name, task)): Path<(String, String)>| async move {
+        if task_tracker.get(&name).is_none() {
+            return Err(not_found(format!("Job not found: {}", name)));
+        }
+        match TaskTracker::new(name.clone(), task_tracker).get(&task) {
+            Some(task) => Ok(Json(task)),
+            None => Err(not_found(format!("Task not found: {}/{}", name, task))),
+        }
+    };
+
+    let step_tracker = tracker.clone();
+    let get_step = |Path((name, task, index)): Path<(String, String, usize)>| async move {
+        if step_tracker.get(&name).is_none() {
+            return Err(not_found(format!("Job not found: {}", name)));
+        }
+        let task_tracker = TaskTracker::new(name.clone(), step_tracker);
+        if task_tracker.get(&task).is_none() {
+            return Err(not_found(format!("Task not found: {}/{}", name, task)));
+        }
+        match StepTracker::new(task.clone(), task_tracker).get(index) {
+            Some(step) => Ok(Json(step)),
+            None => Err(not_found(format!("Step not found: {}/{}/{}", name, task, index))),
+        }
+    };
 
-    let build_future = tokio::spawn(async move {
-        loader.load()?;
-        loader.runner().run(tracker_clone).await?;
-        Ok::<(), bed::Error>(())
-    });
+    let pipeline_tracker = tracker.clone();
+    let get_pipeline = || async move { Json(PipelineStatus::new(pipeline_tracker.list())) };
+
+    let artifacts_tracker = tracker.clone();
+    let get_artifacts = |name: Path<String>| async move {
+        match artifacts_tracker.get(&name) {
+            Some(job) => Ok(Json(job.artifacts)),
+            None => Err(not_found(format!("Job not found: {}", name.as_str()))),
+        }
+    };
 
     let get_job = |name: Path<String>| async move {
-        let job = tracker.get(&name);
-        Json(job)
+        match tracker.get(&name) {
+            Some(job) => Ok(Json(job)),
+            None => Err(not_found(format!("Job not found: {}", name.as_str()))),
+        }
     };
 
-    let app = Router::new()
-        .route("/job/:name", get(get_job));
+    let mut app = Router::new()
+        .route("/metrics", get(get_metrics))
+        .route("/pipeline", get(get_pipeline))
+        .route("/run", post(run_pipeline))
+        .route("/runs", get(list_runs))
+        .route("/runs/:id", get(get_run))
+        .route("/job/:name", get(get_job))
+        .route("/job/:name/artifacts", get(get_artifacts))
+        .route("/job/:name/cancel", post(cancel_job))
+        .route("/job/:name/retry", post(retry_job))
+        .route("/job/:name/task/:task", get(get_task))
+        .route("/job/:name/task/:task/step/:index", get(get_step))
+        .route("/ws", get(ws));
+    if let Some(token) = args.auth_token.clone() {
+        let token: Arc<str> = token.into();
+        app = app.route_layer(middleware::from_fn(move |request: Request, next: Next| {
+            authorize(token.clone(), request, next)
+        }));
+    }
+    // The dashboard page itself is static, so it's left open; it passes a
+    // token given as #token=<TOKEN> on to the API
+    if args.ui {
+        app = app.route("/", get(|| async { Html(DASHBOARD) }));
+    }
+    if !args.cors_origin.is_empty() {
+        app = app.layer(cors(&args.cors_origin));
+    }
     let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await?;
 
     axum::serve(listener, app).await?;
 
-    build_future.await??;
+    let mut build_future = build_future.lock().await;
+    (&mut *build_future).await??;
 
     Ok(())
 }
+
+/// Watch a directory or file for changes, reporting each one on the returned
+/// channel for as long as the watcher is kept.
+fn watch(path: &std::path::Path) -> Result<(notify::RecommendedWatcher, mpsc::UnboundedReceiver<()>), bed::Error> {
+    let (sender, receiver) = mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        if let Ok(event) = event {
+            // Reading the pipeline files shouldn't trigger another run
+            if !event.kind.is_access() {
+                let _ = sender.send(());
+            }
+        }
+    }).map_err(std::io::Error::other)?;
+    watcher.watch(path, notify::RecursiveMode::Recursive)
+        .map_err(std::io::Error::other)?;
+    Ok((watcher, receiver))
+}
+
+/// Wait for a change and let the rest of its burst settle. Returns false once
+/// the watcher has gone away.
+async fn next_change(changes: &mut mpsc::UnboundedReceiver<()>) -> bool {
+    if changes.recv().await.is_none() {
+        return false;
+    }
+    while let Ok(Some(())) = tokio::time::timeout(Duration::from_millis(300), changes.recv()).await {}
+    true
+}
+
+/// Pass on requests bearing `token`, and turn away the rest with 401. The
+/// WebSocket may take it percent-encoded as `?token=<TOKEN>` instead, since
+/// browsers can't set headers on the upgrade request.
+async fn authorize(token: Arc<str>, request: Request, next: Next) -> Response {
+    let bearer = request.headers().get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    let query = match request.uri().path() {
+        "/ws" => Query::<HashMap<String, String>>::try_from_uri(request.uri()).ok()
+            .and_then(|Query(mut query)| query.remove("token")),
+        _ => None,
+    };
+    if bearer.or(query.as_deref()).is_some_and(|given| constant_time_eq(given.as_bytes(), token.as_bytes())) {
+        return next.run(request).await;
+    }
+    let body = ErrorBody { error: "Missing or invalid bearer token".to_string() };
+    (StatusCode::UNAUTHORIZED, [(header::WWW_AUTHENTICATE, "Bearer")], Json(body)).into_response()
+}
+
+/// Compare secrets without stopping at the first difference.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Allow cross-origin requests from `origins`, answering preflight requests.
+fn cors(origins: &[HeaderValue]) -> CorsLayer {
+    let origins = if origins.iter().any(|origin| origin == "*") {
+        AllowOrigin::any()
+    } else {
+        AllowOrigin::list(origins.iter().cloned())
+    };
+    CorsLayer::new()
+        .allow_origin(origins)
+        .allow_methods([Method::GET, Method::POST])
+        // Echo the requested headers back rather than "*", which browsers
+        // don't take to cover Authorization
+        .allow_headers(AllowHeaders::mirror_request())
+}
+
+fn error(status: StatusCode, error: bed::Error) -> (StatusCode, Json<ErrorBody>) {
+    (status, Json(ErrorBody { error: error.to_string() }))
+}
+
+fn not_found(error: String) -> (StatusCode, Json<ErrorBody>) {
+    (StatusCode::NOT_FOUND, Json(ErrorBody { error }))
+}
+
+/// Push every status change to a WebSocket client as a JSON `StatusEvent`.
+async fn stream_updates(mut socket: WebSocket, tracker: JobTracker) {
+    let mut updates = tracker.subscribe();
+
+    loop {
+        tokio::select! {
+            update = updates.recv() => match update {
+                Ok(event) => {
+                    let json = match serde_json::to_string(&event) {
+                        Ok(json) => json,
+                        Err(_) => continue,
+                    };
+                    if socket.send(Message::Text(json)).await.is_err() {
+                        break;
+                    }
+                }
+                // A slow client misses intermediate updates but keeps going
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => break,
+            },
+            message = socket.recv() => match message {
+                // Incoming messages are reserved for future control commands
+                Some(Ok(_)) => continue,
+                _ => break,
+            },
+        }
+    }
+}
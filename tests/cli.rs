@@ -0,0 +1,62 @@
+use std::process::Command;
+
+#[test]
+fn exec_exits_with_the_command_status() {
+    let output = Command::new(env!("CARGO_BIN_EXE_bed"))
+        .args(["exec", "--", "sh", "-c", "exit 3"])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(3));
+}
+
+#[test]
+fn exec_succeeds_with_the_command() {
+    let output = Command::new(env!("CARGO_BIN_EXE_bed"))
+        .args(["exec", "--", "echo", "hello"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+}
+
+#[test]
+fn run_takes_options_after_the_subcommand() {
+    let dir = tempfile::tempdir().unwrap();
+    let pipeline = "name: unit\ntags: [test]\ntasks:\n  - name: main\n    steps:\n      - args: 'true'\n\
+        ---\nname: deploy\ntasks:\n  - name: main\n    steps:\n      - args: 'false'\n";
+    std::fs::write(dir.path().join("pipeline.yaml"), pipeline).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_bed"))
+        .arg("run")
+        .arg("--directory")
+        .arg(dir.path())
+        .args(["--tag", "test"])
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("unit") && !stdout.contains("deploy"), "{}", stdout);
+}
+
+#[test]
+fn exec_masks_secrets_and_mirrors_output() {
+    let output = Command::new(env!("CARGO_BIN_EXE_bed"))
+        .env("TOKEN", "hunter2")
+        .args(["exec", "--secret-env", "TOKEN", "--mirror-output", "--", "echo", "token hunter2"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.lines().any(|line| line == "token ***"), "{}", stdout);
+    assert!(!stdout.contains("hunter2") && !String::from_utf8_lossy(&output.stderr).contains("hunter2"));
+}
+
+#[test]
+fn command_wrapper_keeps_quoted_arguments() {
+    let output = Command::new(env!("CARGO_BIN_EXE_bed"))
+        .args(["exec", "--mirror-output", "--command-wrapper", "echo 'wrapped  by'", "--", "hello"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.lines().any(|line| line == "wrapped  by hello"), "{}", stdout);
+}